@@ -0,0 +1,24 @@
+#include <stdio.h>
+
+#include "25-lib.h"
+
+struct Point {
+    int64_t x, y;
+};
+
+static int64_t triple(int64_t n) { return n * 3; }
+
+int main(void) {
+    struct Point a = {1, 2}, b = {4, 6};
+    greet();
+    printf("%ld\n", (long)dist2(&a, &b));
+    printf("%ld\n", (long)apply(triple, 14));
+
+    // A str is a 64-bit length followed by the bytes
+    struct {
+        int64_t len;
+        char bytes[12];
+    } s = {11, "hello world"};
+    printf("%ld\n", (long)count_byte((uint8_t *)&s, 'o'));
+    return 0;
+}
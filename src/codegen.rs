@@ -6,10 +6,22 @@ pub mod x86_64 {
     };
 
     use crate::{
-        ir::{Instruction, Program, Value},
-        parser::{BinOp, CmpOp},
+        ir::{AsmPart, Instruction, Program, Value},
+        parser as ast,
     };
 
+    /// Writes the message pointed to by `rdi` to stderr and exits with 1
+    const BOUNDS_FAIL: &[u8] = b"bounds_fail:
+    mov rdx, qword [rdi]
+    lea rsi, [rdi+8]
+    mov rdi, 2
+    mov rax, 1
+    syscall
+    mov rax, 60
+    mov rdi, 1
+    syscall
+";
+
     pub trait Compile {
         fn compile_to_asm(&self, path: &str) -> std::io::Result<()>;
     }
@@ -20,27 +32,78 @@ pub mod x86_64 {
 
             f.write_all(b"format ELF64 \n")?;
             f.write_all(b"section '.text' executable\n")?;
-            f.write_all(b"extrn print\n")?; // TODO: unhardcode functions
-            f.write_all(b"extrn printn\n")?;
-            f.write_all(b"extrn print_num\n")?;
-            f.write_all(b"extrn read\n")?;
-            f.write_all(b"public _start\n")?;
+            for name in &self.externs {
+                f.write_all(format!("extrn {name}\n").as_bytes())?;
+            }
+            // Under libc, top-level code is called by the C runtime's own
+            // `_start` and leaves through `exit` so stdio gets flushed.
+            // Libraries have no top-level code at all.
+            let entry = if self.libc { "main" } else { "_start" };
+            if self.fn_bodies.contains_key("_start") {
+                let mut needed = vec!["jacl_init_args"];
+                if self.libc {
+                    needed.push("exit");
+                }
+                // The program may have declared them itself
+                for name in needed {
+                    if !self.externs.iter().any(|e| e == name) {
+                        f.write_all(format!("extrn {name}\n").as_bytes())?;
+                    }
+                }
+                f.write_all(format!("public {entry}\n").as_bytes())?;
+            }
+            for (name, _) in &self.exports {
+                f.write_all(format!("public {name}\n").as_bytes())?;
+            }
 
             for (name, code) in &self.fn_bodies {
-                let num_vars = self.scopes.get(name).unwrap().len();
-                compile_fun(&mut f, name, code, num_vars)?;
+                let frame = Frame {
+                    num_vars: self.scopes[name].len(),
+                    num_temps: self.temps[name],
+                    buf_size: self.local_bufs[name],
+                    libc: self.libc,
+                };
+                if name == "_start" {
+                    f.write_all(format!("{entry}:\n").as_bytes())?;
+                    if self.libc {
+                        // `main` gets argc, argv and envp as arguments. Its
+                        // return address leaves rsp 8 bytes off alignment.
+                        f.write_all(b"    sub rsp, 8\n")?;
+                        f.write_all(b"    call jacl_init_args\n")?;
+                        f.write_all(b"    add rsp, 8\n")?;
+                    } else {
+                        // The kernel leaves argc on top of the stack, followed
+                        // by the argv and envp arrays
+                        f.write_all(b"    mov rdi, qword [rsp]\n")?;
+                        f.write_all(b"    lea rsi, [rsp+8]\n")?;
+                        f.write_all(b"    lea rdx, [rsi+rdi*8+8]\n")?;
+                        f.write_all(b"    call jacl_init_args\n")?;
+                        // It enters `_start` with rsp 16-byte aligned, while
+                        // functions get a return address on top of that
+                        f.write_all(b"    sub rsp, 8\n")?;
+                    }
+                } else {
+                    f.write_all(format!("{name}:\n").as_bytes())?;
+                }
+                compile_body(&mut f, code, &frame)?;
+            }
+
+            if self.bounds_check {
+                f.write_all(BOUNDS_FAIL)?;
             }
 
             f.write_all(b"section '.data' writable\n")?;
 
             for (id, string) in self.strings.iter().enumerate() {
-                f.write_all(format!("str{id}: db {}", string.len()).as_bytes())?;
+                // A 64-bit length, so literals can be any size
+                f.write_all(format!("str{id}: dq {}\n    db ", string.len()).as_bytes())?;
 
                 for byte in string.as_bytes() {
-                    f.write_all(b",")?;
                     f.write_all(byte.to_string().as_bytes())?;
+                    f.write_all(b",")?;
                 }
-                f.write_all(b"\n")?;
+                // Terminated as well, so `str_data(s)` can be passed to C
+                f.write_all(b"0\n")?;
             }
 
             f.write_all(b"section '.bss' writable\n")?;
@@ -52,16 +115,37 @@ pub mod x86_64 {
         }
     }
 
-    fn compile_fun(
-        f: &mut File,
-        name: &String,
-        code: &Vec<Instruction>,
+    /// Stack layout of a function: variables first, then temporaries, each
+    /// taking one 8-byte slot below `rbp`, then the local buffers.
+    struct Frame {
         num_vars: usize,
-    ) -> std::io::Result<()> {
-        f.write_all(format!("{}:\n", name).as_bytes())?;
+        num_temps: usize,
+        buf_size: usize,
+        libc: bool,
+    }
+
+    impl Frame {
+        /// Rounded up so rsp stays 16-byte aligned at every call
+        fn size(&self) -> usize {
+            ((self.num_vars + self.num_temps) * 8 + self.buf_size).next_multiple_of(16)
+        }
 
+        fn local_buf(&self, offset: usize) -> String {
+            format!("[rbp-{}]", (self.num_vars + self.num_temps) * 8 + offset)
+        }
+
+        fn var(&self, id: usize) -> String {
+            format!("[rbp-{}]", (id + 1) * 8)
+        }
+
+        fn temp(&self, id: usize) -> String {
+            self.var(self.num_vars + id)
+        }
+    }
+
+    fn compile_body(f: &mut File, code: &[Instruction], frame: &Frame) -> std::io::Result<()> {
         for instruction in code {
-            compile_inst_to_asm(f, instruction, num_vars)?;
+            compile_inst_to_asm(f, instruction, frame)?;
         }
 
         Ok(())
@@ -76,63 +160,254 @@ pub mod x86_64 {
         Register::R9,
     ];
 
-    fn compile_inst_to_asm(
-        f: &mut File,
-        inst: &Instruction,
-        num_vars: usize,
-    ) -> std::io::Result<()> {
+    /// The number goes in rax, and r10 takes the place of rcx, which the
+    /// syscall instruction overwrites
+    const SYSCALL_CONVENTION: [Register; 7] = [
+        Register::Rax,
+        Register::Rdi,
+        Register::Rsi,
+        Register::Rdx,
+        Register::R10,
+        Register::R8,
+        Register::R9,
+    ];
+
+    fn compile_inst_to_asm(f: &mut File, inst: &Instruction, frame: &Frame) -> std::io::Result<()> {
         use Instruction::*;
         f.write_all(format!("    ;; {inst:?}\n").as_bytes())?;
         match inst {
+            Loc(_) => {}
             &Prologue(num_params) => {
                 f.write_all(b"    push rbp\n")?;
                 f.write_all(b"    mov rbp, rsp\n")?;
-                f.write_all(format!("    sub rsp, {}\n", num_vars * 8).as_bytes())?;
+                f.write_all(format!("    sub rsp, {}\n", frame.size()).as_bytes())?;
 
                 for (i, reg) in CALL_CONVENTION.iter().take(num_params).enumerate() {
-                    f.write_all(format!("    mov [rbp-{}], {}\n", i * 8, reg).as_bytes())?;
+                    f.write_all(format!("    mov {}, {}\n", frame.var(i), reg).as_bytes())?;
+                }
+
+                // Local buffers start out zeroed, just like the ones in .bss
+                if frame.buf_size > 0 {
+                    // Padding for alignment may sit between them and rsp
+                    f.write_all(
+                        format!("    lea rdi, {}\n", frame.local_buf(frame.buf_size)).as_bytes(),
+                    )?;
+                    f.write_all(format!("    mov rcx, {}\n", frame.buf_size).as_bytes())?;
+                    f.write_all(b"    xor eax, eax\n")?;
+                    f.write_all(b"    rep stosb\n")?;
                 }
             }
             Return(val) => {
-                move_value_into_register(f, val, Register::Rax)?;
+                move_value_into_register(f, val, Register::Rax, frame)?;
                 f.write_all(b"    leave\n")?;
                 f.write_all(b"    ret\n")?;
             }
+            // The C library's exit flushes its buffers first
+            Exit(code) if frame.libc => {
+                move_value_into_register(f, code, Register::Rdi, frame)?;
+                f.write_all(b"    call exit\n")?;
+            }
             Exit(code) => {
+                move_value_into_register(f, code, Register::Rdi, frame)?;
                 f.write_all(b"    mov rax, 60\n")?;
-                f.write_all(format!("    mov rdi, {code}\n").as_bytes())?;
                 f.write_all(b"    syscall\n")?;
             }
             VarAssign(id, value) => {
-                move_value_into_register(f, value, Register::Rdi)?;
-                f.write_all(format!("    mov [rbp-{}], rdi\n", id * 8).as_bytes())?;
+                move_value_into_register(f, value, Register::Rax, frame)?;
+                f.write_all(format!("    mov {}, rax\n", frame.var(*id)).as_bytes())?;
+            }
+            BinOp(dst, op, a, b) => {
+                move_value_into_register(f, a, Register::Rax, frame)?;
+                move_value_into_register(f, b, Register::Rcx, frame)?;
+
+                f.write_all(match op {
+                    ast::BinOp::Plus => b"    add rax, rcx\n",
+                    ast::BinOp::Minus => b"    sub rax, rcx\n",
+                    ast::BinOp::Mult => b"    mul rcx\n",
+                    ast::BinOp::Div => b"    xor rdx, rdx\n    div rcx\n",
+                })?;
+
+                f.write_all(format!("    mov {}, rax\n", frame.temp(*dst)).as_bytes())?;
+            }
+            CmpOp(dst, op, a, b) => {
+                move_value_into_register(f, a, Register::Rax, frame)?;
+                move_value_into_register(f, b, Register::Rcx, frame)?;
+
+                f.write_all(b"    cmp rax, rcx\n")?;
+                f.write_all(b"    mov rax, 0\n")?;
+                f.write_all(b"    mov rcx, 1\n")?;
+                f.write_all(match op {
+                    ast::CmpOp::Less => b"    cmovb rax, rcx\n",
+                    ast::CmpOp::Equal => b"    cmove rax, rcx\n",
+                    ast::CmpOp::Greater => b"    cmova rax, rcx\n",
+                    ast::CmpOp::LtEq => b"    cmovbe rax, rcx\n",
+                    ast::CmpOp::GtEq => b"    cmovae rax, rcx\n",
+                })?;
+
+                f.write_all(format!("    mov {}, rax\n", frame.temp(*dst)).as_bytes())?;
+            }
+            PtrAccess(dst, ptr, size) => {
+                move_value_into_register(f, ptr, Register::Rax, frame)?;
+                f.write_all(match size {
+                    1 => b"    movzx rax, byte [rax]\n",
+                    2 => b"    movzx rax, word [rax]\n",
+                    4 => b"    mov eax, dword [rax]\n",
+                    8 => b"    mov rax, qword [rax]\n",
+                    _ => panic!("unsupported operand size: {size}"),
+                })?;
+                f.write_all(format!("    mov {}, rax\n", frame.temp(*dst)).as_bytes())?;
+            }
+            FuncCall(dst, name, args) => {
+                // The type checker keeps calls to at most six arguments
+
+                // Arguments are already evaluated, so loading one can't clobber another
+                for (arg, reg) in args.iter().zip(CALL_CONVENTION) {
+                    move_value_into_register(f, arg, reg, frame)?;
+                }
+
+                // Variadic functions read the number of vector registers
+                // used from al, and we never pass floats
+                f.write_all(b"    xor eax, eax\n")?;
+                f.write_all(format!("    call {name}\n").as_bytes())?;
+                f.write_all(format!("    mov {}, rax\n", frame.temp(*dst)).as_bytes())?;
+            }
+            Syscall(dst, args) => {
+                for (arg, reg) in args.iter().zip(SYSCALL_CONVENTION) {
+                    move_value_into_register(f, arg, reg, frame)?;
+                }
+
+                f.write_all(b"    syscall\n")?;
+                f.write_all(format!("    mov {}, rax\n", frame.temp(*dst)).as_bytes())?;
+            }
+            IndirectCall(dst, callee, args) => {
+                for (arg, reg) in args.iter().zip(CALL_CONVENTION) {
+                    move_value_into_register(f, arg, reg, frame)?;
+                }
+                move_value_into_register(f, callee, Register::Rax, frame)?;
+
+                f.write_all(b"    call rax\n")?;
+                f.write_all(format!("    mov {}, rax\n", frame.temp(*dst)).as_bytes())?;
             }
             Label(id) => {
                 f.write_all(format!("label{id}:\n").as_bytes())?;
             }
             JmpIfZero(cond, label_id) => {
-                move_value_into_register(f, cond, Register::Rax)?;
+                move_value_into_register(f, cond, Register::Rax, frame)?;
                 f.write_all(b"    test rax, rax\n")?;
                 f.write_all(format!("    jz label{label_id}\n").as_bytes())?;
             }
             Jmp(label_id) => {
                 f.write_all(format!("    jmp label{label_id}\n").as_bytes())?;
             }
-            PtrAssign(ptr, val) => {
-                move_value_into_register(f, ptr, Register::Rax)?;
-                f.write_all(b"    push rax\n")?;
-                move_value_into_register(f, val, Register::Rbx)?;
-                f.write_all(b"    pop rax\n")?;
-                f.write_all(b"    mov [rax], bl\n")?; // TODO: support different operand sizes
+            PtrAssign(ptr, val, size) => {
+                move_value_into_register(f, ptr, Register::Rax, frame)?;
+                move_value_into_register(f, val, Register::Rcx, frame)?;
+                f.write_all(match size {
+                    1 => b"    mov byte [rax], cl\n",
+                    2 => b"    mov word [rax], cx\n",
+                    4 => b"    mov dword [rax], ecx\n",
+                    8 => b"    mov qword [rax], rcx\n",
+                    _ => panic!("unsupported operand size: {size}"),
+                })?;
+            }
+            BoundsCheck(index, len, msg) => {
+                move_value_into_register(f, index, Register::Rax, frame)?;
+                f.write_all(format!("    mov rcx, {len}\n").as_bytes())?;
+                f.write_all(b"    cmp rax, rcx\n")?;
+                f.write_all(b"    jb @f\n")?;
+                f.write_all(format!("    mov rdi, str{msg}\n").as_bytes())?;
+                f.write_all(b"    jmp bounds_fail\n")?;
+                f.write_all(b"@@:\n")?;
             }
-            EvalValue(val) => {
-                move_value_into_register(f, val, Register::Rax)?;
+            Zero(addr, size) => {
+                move_value_into_register(f, addr, Register::Rdi, frame)?;
+                f.write_all(format!("    mov rcx, {size}\n").as_bytes())?;
+                f.write_all(b"    xor eax, eax\n")?;
+                f.write_all(b"    rep stosb\n")?;
+            }
+            Asm(parts, clobbers) => {
+                let saved: Vec<_> = clobbers
+                    .iter()
+                    .filter(|reg| CALLEE_SAVED.contains(&reg.as_str()))
+                    .collect();
+                for reg in &saved {
+                    f.write_all(format!("    push {reg}\n").as_bytes())?;
+                }
+
+                let mut text = String::new();
+                for part in parts {
+                    match part {
+                        AsmPart::Text(part) => text.push_str(part),
+                        &AsmPart::Var(id) => text.push_str(&frame.var(id)),
+                    }
+                }
+                for line in text.lines() {
+                    f.write_all(format!("    {}\n", line.trim()).as_bytes())?;
+                }
+
+                for reg in saved.iter().rev() {
+                    f.write_all(format!("    pop {reg}\n").as_bytes())?;
+                }
+            }
+            Switch(value, cases, default) => {
+                move_value_into_register(f, value, Register::Rax, frame)?;
+                if is_dense(cases) {
+                    let low = cases.iter().map(|&(low, _, _)| low).min().unwrap();
+                    let high = cases.iter().map(|&(_, high, _)| high).max().unwrap();
+                    let mut table = vec![*default; high - low + 1];
+                    for &(l, h, label) in cases {
+                        table[l - low..=h - low].fill(label);
+                    }
+
+                    f.write_all(format!("    mov rcx, {low}\n").as_bytes())?;
+                    f.write_all(b"    sub rax, rcx\n")?;
+                    f.write_all(format!("    mov rcx, {}\n", table.len()).as_bytes())?;
+                    f.write_all(b"    cmp rax, rcx\n")?;
+                    f.write_all(format!("    jae label{default}\n").as_bytes())?;
+                    f.write_all(format!("    lea rcx, [table{default}]\n").as_bytes())?;
+                    f.write_all(b"    jmp qword [rcx+rax*8]\n")?;
+                    let labels: Vec<_> = table.iter().map(|id| format!("label{id}")).collect();
+                    f.write_all(format!("table{default}: dq {}\n", labels.join(",")).as_bytes())?;
+                } else {
+                    for &(low, high, label) in cases {
+                        f.write_all(format!("    mov rcx, {low}\n").as_bytes())?;
+                        f.write_all(b"    cmp rax, rcx\n")?;
+                        if low == high {
+                            f.write_all(format!("    je label{label}\n").as_bytes())?;
+                        } else {
+                            f.write_all(b"    jb @f\n")?;
+                            f.write_all(format!("    mov rcx, {high}\n").as_bytes())?;
+                            f.write_all(b"    cmp rax, rcx\n")?;
+                            f.write_all(format!("    jbe label{label}\n").as_bytes())?;
+                            f.write_all(b"@@:\n")?;
+                        }
+                    }
+                    f.write_all(format!("    jmp label{default}\n").as_bytes())?;
+                }
             }
         }
 
         Ok(())
     }
 
+    /// Registers the SysV ABI requires functions to preserve. `asm` blocks
+    /// clobbering them get them saved around the block.
+    const CALLEE_SAVED: [&str; 5] = ["rbx", "r12", "r13", "r14", "r15"];
+
+    /// Whether a jump table for the cases would be small and mostly filled,
+    /// beating a chain of compares
+    fn is_dense(cases: &[(usize, usize, usize)]) -> bool {
+        let Some(low) = cases.iter().map(|&(low, _, _)| low).min() else {
+            return false;
+        };
+        let high = cases.iter().map(|&(_, high, _)| high).max().unwrap();
+        if cases.len() < 4 || high - low >= 1024 {
+            return false;
+        }
+        let covered: usize = cases.iter().map(|&(low, high, _)| high - low + 1).sum();
+        high - low < 3 * covered
+    }
+
     #[allow(dead_code)]
     #[derive(Clone, Copy)]
     enum Register {
@@ -177,100 +452,39 @@ pub mod x86_64 {
         }
     }
 
-    impl Register {
-        fn as_8bit(&self) -> String {
-            match self {
-                Register::Rax => "al".into(),
-                Register::Rbx => "bl".into(),
-                Register::Rcx => "cl".into(),
-                Register::Rdx => "dl".into(),
-                Register::Rbp => "bl".into(),
-                Register::Rsp => "sl".into(),
-                Register::Rsi => "sil".into(),
-                Register::Rdi => "dil".into(),
-                Register::R8 => "r8d".into(),
-                Register::R9 => "r9d".into(),
-                Register::R10 => "r10d".into(),
-                Register::R11 => "r11d".into(),
-                Register::R12 => "r12d".into(),
-                Register::R13 => "r13d".into(),
-                Register::R14 => "r14d".into(),
-                Register::R15 => "r15d".into(),
-            }
-        }
-    }
-
-    fn move_value_into_register(f: &mut File, value: &Value, reg: Register) -> std::io::Result<()> {
-        f.write_all(format!("    ;; {reg} <- {value:?}\n").as_bytes())?;
+    /// Loads an operand into `reg`. Only touches `reg`, so operands can be
+    /// loaded one after another without clobbering each other.
+    fn move_value_into_register(
+        f: &mut File,
+        value: &Value,
+        reg: Register,
+        frame: &Frame,
+    ) -> std::io::Result<()> {
         match value {
             Value::Void => {}
             &Value::String(id) => {
                 f.write_all(format!("    mov {}, str{id}\n", reg).as_bytes())?;
             }
             &Value::FromVar(id) => {
-                f.write_all(format!("    mov {}, [rbp-{}]\n", reg, id * 8).as_bytes())?;
+                f.write_all(format!("    mov {}, {}\n", reg, frame.var(id)).as_bytes())?;
             }
             &Value::Int(int) => {
                 f.write_all(format!("    mov {}, {int}\n", reg).as_bytes())?;
             }
-            Value::BinOp(op, a, b) => {
-                move_value_into_register(f, a, Register::Rax)?;
-                move_value_into_register(f, b, Register::Rbx)?;
-
-                if let BinOp::Div = op {
-                    f.write_all(b"    push rdx\n")?;
-                    f.write_all(b"    xor rdx, rdx\n")?;
-                    f.write_all(b"    div rbx\n")?;
-                    f.write_all(b"    pop rdx\n")?;
-                } else {
-                    f.write_all(match op {
-                        BinOp::Plus => b"    add rax, rbx\n",
-                        BinOp::Minus => b"    sub rax, rbx\n",
-                        BinOp::Mult => b"    mul rbx\n",
-                        BinOp::Div => panic!("unreachable"),
-                    })?;
-                }
-
-                f.write_all(format!("    mov {}, rax\n", reg).as_bytes())?;
-            }
-            Value::CmpOp(op, a, b) => {
-                move_value_into_register(f, a, Register::Rax)?;
-                move_value_into_register(f, b, Register::Rbx)?;
-
-                f.write_all(b"    cmp rax, rbx\n")?;
-                f.write_all(b"    mov rax, 0\n")?;
-                f.write_all(b"    mov rbx, 1\n")?;
-                f.write_all(match op {
-                    CmpOp::Less => b"    cmovb rax, rbx\n",
-                    CmpOp::Equal => b"    cmove rax, rbx\n",
-                    CmpOp::Greater => b"    cmova rax, rbx\n",
-                    CmpOp::LtEq => b"    cmovbe rax, rbx\n",
-                    CmpOp::GtEq => b"    cmovae rax, rbx\n",
-                })?;
-
-                f.write_all(format!("    mov {}, rax\n", reg).as_bytes())?;
-            }
             &Value::Buf(id) => {
                 f.write_all(format!("    mov {reg}, buf{id}\n").as_bytes())?;
             }
-            Value::PtrAccess(ptr) => {
-                move_value_into_register(f, ptr, reg)?;
-                // TODO: support different operand sizes
-                f.write_all(format!("    mov {reg_lower}, [{reg}]\n", reg_lower = reg.as_8bit()).as_bytes())?;
-                f.write_all(format!("    and {reg}, 255\n").as_bytes())?;
+            &Value::LocalBuf(offset) => {
+                f.write_all(format!("    lea {}, {}\n", reg, frame.local_buf(offset)).as_bytes())?;
             }
             &Value::VarAddr(id) => {
-                f.write_all(format!("    lea {}, [rbp-{}]\n", reg, id * 8).as_bytes())?;
+                f.write_all(format!("    lea {}, {}\n", reg, frame.var(id)).as_bytes())?;
             }
-            Value::FuncCall(name, args) => {
-                // TODO: add support for more than six args
-
-                for (arg, reg) in args.iter().zip(CALL_CONVENTION) {
-                    move_value_into_register(f, arg, reg)?;
-                }
-
-                f.write_all(format!("    call {name}\n").as_bytes())?;
-                f.write_all(format!("    mov {reg}, rax\n").as_bytes())?;
+            &Value::Temp(id) => {
+                f.write_all(format!("    mov {}, {}\n", reg, frame.temp(id)).as_bytes())?;
+            }
+            Value::FuncAddr(name) => {
+                f.write_all(format!("    mov {reg}, {name}\n").as_bytes())?;
             }
         }
         Ok(())
@@ -0,0 +1,196 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+};
+
+use crate::{
+    ir::{IRError, Instruction, Program, Value},
+    loc::Loc,
+    parser::Type,
+};
+
+#[derive(Debug)]
+pub struct Warning(Loc, String);
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Warning(loc, msg) = self;
+        f.write_fmt(format_args!("{}: warning: {}", loc, msg))
+    }
+}
+
+/// Control-flow checks over the IR: functions whose result is used must not
+/// be able to run off their end, and statements that can never run are
+/// reported as warnings.
+pub fn check(prog: &Program) -> Result<Vec<Warning>, IRError> {
+    let mut warnings = Vec::new();
+    let used = used_for_value(prog);
+
+    let mut names: Vec<_> = prog.fn_bodies.keys().collect();
+    names.sort();
+    for name in names {
+        let code = &prog.fn_bodies[name];
+        let reachable = reachable(code);
+
+        let mut prev_reachable = true;
+        for (inst, &reachable) in code.iter().zip(&reachable) {
+            if let Instruction::Loc(loc) = inst {
+                if !reachable && prev_reachable {
+                    warnings.push(Warning(loc.clone(), "Unreachable statement".into()));
+                }
+                prev_reachable = reachable;
+            }
+        }
+
+        // FuncDef lowering always ends the body with an implicit valueless
+        // return. Explicit ones only lack a value in void functions, whose
+        // results the type checker never lets anyone use
+        let falls_off = matches!(code.last(), Some(Instruction::Return(Value::Void)))
+            && *reachable.last().unwrap();
+        if falls_off && used.contains(name) {
+            let Some(Instruction::Loc(loc)) = code.first() else {
+                panic!("function {name} doesn't start with its location");
+            };
+            return Err(IRError(
+                loc.clone(),
+                format!("Function {name} is used for its value but can end without returning"),
+            ));
+        }
+    }
+
+    warnings.sort_by(|Warning(a, _), Warning(b, _)| a.cmp(b));
+    Ok(warnings)
+}
+
+fn reachable(code: &[Instruction]) -> Vec<bool> {
+    let labels: HashMap<usize, usize> = code
+        .iter()
+        .enumerate()
+        .filter_map(|(i, inst)| match inst {
+            &Instruction::Label(id) => Some((id, i)),
+            _ => None,
+        })
+        .collect();
+
+    let mut reachable = vec![false; code.len()];
+    let mut worklist = vec![0];
+    while let Some(i) = worklist.pop() {
+        if i >= code.len() || reachable[i] {
+            continue;
+        }
+        reachable[i] = true;
+
+        match &code[i] {
+            Instruction::Jmp(label) => worklist.push(labels[label]),
+            // Constant loop conditions come from things like `while 1`. A
+            // constant `if` still gets both of its branches checked.
+            Instruction::JmpIfZero(Value::Int(cond), label)
+                if exits_loop(code, &labels, i, *label) =>
+            {
+                if *cond == 0 {
+                    worklist.push(labels[label]);
+                } else {
+                    worklist.push(i + 1);
+                }
+            }
+            Instruction::JmpIfZero(_, label) => {
+                worklist.push(labels[label]);
+                worklist.push(i + 1);
+            }
+            Instruction::Switch(_, cases, default) => {
+                worklist.extend(cases.iter().map(|(_, _, label)| labels[label]));
+                worklist.push(labels[default]);
+            }
+            Instruction::Return(_) | Instruction::Exit(_) => {}
+            _ => worklist.push(i + 1),
+        }
+    }
+    reachable
+}
+
+/// Whether the jump at `i` to `label` leaves a loop, whose body ends by
+/// jumping back to before the condition
+fn exits_loop(
+    code: &[Instruction],
+    labels: &HashMap<usize, usize>,
+    i: usize,
+    label: usize,
+) -> bool {
+    let end = labels[&label];
+    match end.checked_sub(1).map(|j| &code[j]) {
+        Some(Instruction::Jmp(start)) => labels[start] < i,
+        _ => false,
+    }
+}
+
+/// Names of the functions whose return value is read by some caller. Any
+/// function whose address is taken may be behind an indirect call, and C may
+/// read the result of any exported function returning one.
+fn used_for_value(prog: &Program) -> HashSet<String> {
+    let mut used = HashSet::new();
+    let mut indirect_used = false;
+    for code in prog.fn_bodies.values() {
+        let read: HashSet<usize> = code
+            .iter()
+            .flat_map(operands)
+            .filter_map(|val| match val {
+                &Value::Temp(id) => Some(id),
+                _ => None,
+            })
+            .collect();
+
+        for inst in code {
+            match inst {
+                Instruction::FuncCall(dst, name, _) if read.contains(dst) => {
+                    used.insert(name.clone());
+                }
+                Instruction::IndirectCall(dst, ..) if read.contains(dst) => indirect_used = true,
+                _ => {}
+            }
+        }
+    }
+
+    if indirect_used {
+        let addressed = prog
+            .fn_bodies
+            .values()
+            .flatten()
+            .flat_map(operands)
+            .filter_map(|val| match val {
+                Value::FuncAddr(name) => Some(name.clone()),
+                _ => None,
+            });
+        used.extend(addressed);
+    }
+
+    let exported = prog
+        .exports
+        .iter()
+        .filter(|(_, ret)| *ret != Type::Void)
+        .map(|(name, _)| name.clone());
+    used.extend(exported);
+    used
+}
+
+fn operands(inst: &Instruction) -> Vec<&Value> {
+    match inst {
+        Instruction::VarAssign(_, val)
+        | Instruction::PtrAccess(_, val, _)
+        | Instruction::JmpIfZero(val, _)
+        | Instruction::Return(val)
+        | Instruction::Exit(val)
+        | Instruction::BoundsCheck(val, ..)
+        | Instruction::Zero(val, _)
+        | Instruction::Switch(val, ..) => vec![val],
+        Instruction::BinOp(_, _, a, b)
+        | Instruction::CmpOp(_, _, a, b)
+        | Instruction::PtrAssign(a, b, _) => vec![a, b],
+        Instruction::FuncCall(_, _, args) | Instruction::Syscall(_, args) => args.iter().collect(),
+        Instruction::IndirectCall(_, callee, args) => std::iter::once(callee).chain(args).collect(),
+        Instruction::Loc(_)
+        | Instruction::Label(_)
+        | Instruction::Jmp(_)
+        | Instruction::Prologue(_)
+        | Instruction::Asm(..) => vec![],
+    }
+}
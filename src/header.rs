@@ -0,0 +1,141 @@
+use std::collections::BTreeSet;
+
+use crate::parser::{Node, Type};
+
+/// Writes a C header declaring the exported functions of a checked program,
+/// guarded by `guard`
+pub fn generate(node: &Node, guard: &str) -> String {
+    let mut funcs = Vec::new();
+    let mut structs = BTreeSet::new();
+    collect_exports(node, &mut funcs, &mut structs);
+
+    let mut header = format!("#ifndef {guard}\n#define {guard}\n\n");
+    header.push_str("/* Link with libjacl.a after this library, it holds the jacl runtime */\n\n");
+    header.push_str("#include <stdbool.h>\n#include <stdint.h>\n\n");
+    if !structs.is_empty() {
+        for name in &structs {
+            header.push_str(&format!("struct {};\n", c_name(name)));
+        }
+        header.push('\n');
+    }
+    for func in &funcs {
+        header.push_str(func);
+        header.push_str(";\n");
+    }
+    header.push_str(&format!("\n#endif /* {guard} */\n"));
+    header
+}
+
+/// Turns a file name into an include guard, e.g. `geometry.h` into
+/// `GEOMETRY_H`
+pub fn guard(file_name: &str) -> String {
+    let guard: String = file_name
+        .chars()
+        .map(|ch| {
+            if ch.is_ascii_alphanumeric() {
+                ch.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if guard.starts_with(|ch: char| ch.is_ascii_digit()) {
+        format!("JACL_{guard}")
+    } else {
+        guard
+    }
+}
+
+fn collect_exports(node: &Node, funcs: &mut Vec<String>, structs: &mut BTreeSet<String>) {
+    match node {
+        // Imported files come in as blocks of their definitions
+        Node::Block(_, nodes) => {
+            for node in nodes {
+                collect_exports(node, funcs, structs);
+            }
+        }
+        Node::FuncDef {
+            name,
+            args,
+            ret,
+            export: true,
+            ..
+        } => {
+            // Unannotated parameters and return values are ints
+            let params: Vec<_> = args
+                .iter()
+                .map(|(arg, ty)| {
+                    let ty = ty.clone().unwrap_or(Type::Int);
+                    collect_structs(&ty, structs);
+                    declare(&ty, arg)
+                })
+                .collect();
+            let ret = ret.clone().unwrap_or(Type::Int);
+            collect_structs(&ret, structs);
+            funcs.push(declare(&ret, &format!("{name}{}", param_list(&params))));
+        }
+        _ => {}
+    }
+}
+
+fn collect_structs(ty: &Type, structs: &mut BTreeSet<String>) {
+    match ty {
+        Type::Struct(name) => {
+            structs.insert(name.clone());
+        }
+        Type::Ptr(ty) | Type::Array(ty, _) => collect_structs(ty, structs),
+        Type::Fun(params, ret) => {
+            for param in params {
+                collect_structs(param, structs);
+            }
+            collect_structs(ret, structs);
+        }
+        _ => {}
+    }
+}
+
+fn param_list(params: &[String]) -> String {
+    if params.is_empty() {
+        "(void)".into()
+    } else {
+        format!("({})", params.join(", "))
+    }
+}
+
+/// Qualified names of imported types contain a dot, which C doesn't allow
+fn c_name(name: &str) -> String {
+    name.replace('.', "_")
+}
+
+/// Spells out a C declaration of `name` with type `ty`. Declarators nest
+/// inside out, so pointers to arrays and functions need parentheses.
+fn declare(ty: &Type, name: &str) -> String {
+    let base = |base: &str| {
+        if name.is_empty() {
+            base.into()
+        } else {
+            format!("{base} {name}")
+        }
+    };
+    match ty {
+        Type::Int | Type::Enum(_) => base("int64_t"),
+        Type::Byte => base("uint8_t"),
+        Type::Word => base("uint16_t"),
+        Type::Dword => base("uint32_t"),
+        Type::Bool => base("bool"),
+        Type::Void => base("void"),
+        Type::Struct(name) => base(&format!("struct {}", c_name(name))),
+        // A 64-bit length followed by the bytes
+        Type::Str => declare(&Type::Ptr(Box::new(Type::Byte)), name),
+        Type::Ptr(to) => match **to {
+            Type::Array(..) => declare(to, &format!("(*{name})")),
+            _ => declare(to, &format!("*{name}")),
+        },
+        Type::Array(elem, len) => declare(elem, &format!("{name}[{len}]")),
+        Type::Fun(params, ret) => {
+            // Function values are pointers already
+            let params: Vec<_> = params.iter().map(|param| declare(param, "")).collect();
+            declare(ret, &format!("(*{name}){}", param_list(&params)))
+        }
+    }
+}
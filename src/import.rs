@@ -0,0 +1,373 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    lexer::Lexer,
+    loc::Loc,
+    parser::{parse, Exports, Node, ParseError::Error, ParseResult, Type},
+};
+
+/// Declares the runtime's functions, and gets loaded ahead of every program
+const PRELUDE: &str = "std/prelude.jacl";
+
+/// An imported file, as seen by the files importing it
+#[derive(Clone)]
+pub struct Module {
+    /// Prepended to the names of the file's functions and types, so files
+    /// defining the same names don't clash
+    pub prefix: String,
+    pub exports: Exports,
+}
+
+/// Finds and parses imported files, each only once
+pub struct Loader {
+    search_paths: Vec<PathBuf>,
+    pub debug: bool,
+    /// Files being parsed, importers first, to detect import cycles
+    loading: Vec<PathBuf>,
+    modules: HashMap<PathBuf, Module>,
+    prefixes: HashSet<String>,
+    /// Constants and enums of the prelude, in scope in every file
+    pub prelude: Exports,
+}
+
+impl Loader {
+    pub fn new(search_paths: Vec<PathBuf>, debug: bool) -> Self {
+        Self {
+            search_paths,
+            debug,
+            loading: Vec::new(),
+            modules: HashMap::new(),
+            prefixes: HashSet::new(),
+            prelude: Exports::default(),
+        }
+    }
+
+    /// Parses the file passed on the command line, along with everything it
+    /// imports
+    pub fn parse_main(&mut self, path: &str, code: &str) -> ParseResult<Node> {
+        let loc = Loc::new(path);
+        let prelude = Path::new(PRELUDE)
+            .canonicalize()
+            .map_err(|err| Error(loc.clone(), format!("Cannot find {PRELUDE}: {err}")))?;
+        let (module, prelude) = self.load(&loc, PRELUDE, prelude)?;
+        self.prelude = module.exports;
+
+        if let Ok(path) = Path::new(path).canonicalize() {
+            self.loading.push(path);
+        }
+        let (mut node, _) = parse(Lexer::from_iter(path, code.chars()), self)?;
+        let Node::Block(_, statements) = &mut node else {
+            panic!("a file should parse into a block");
+        };
+        statements.insert(0, prelude);
+        Ok(node)
+    }
+
+    /// Loads the file imported as `path` from `loc`. The first import of a
+    /// file gives its definitions, renamed under the module's prefix, to put
+    /// in place of the import; later ones give a `Nop`.
+    pub fn import(&mut self, loc: &Loc, path: &str) -> ParseResult<(Module, Node)> {
+        let (shown, resolved) = self.resolve(loc, path)?;
+        self.load(loc, &shown, resolved)
+    }
+
+    fn load(&mut self, loc: &Loc, shown: &str, resolved: PathBuf) -> ParseResult<(Module, Node)> {
+        if let Some(i) = self.loading.iter().position(|x| *x == resolved) {
+            let cycle: Vec<_> = self.loading[i..]
+                .iter()
+                .chain([&resolved])
+                .map(|x| x.display().to_string())
+                .collect();
+            return Err(Error(
+                loc.clone(),
+                format!("Import cycle: {}", cycle.join(" -> ")),
+            ));
+        }
+        if let Some(module) = self.modules.get(&resolved) {
+            return Ok((module.clone(), Node::Nop(loc.clone())));
+        }
+
+        let code = fs::read_to_string(&resolved)
+            .map_err(|err| Error(loc.clone(), format!("Cannot read {shown}: {err}")))?;
+        self.loading.push(resolved.clone());
+        let parsed = parse(Lexer::from_iter(shown, code.chars()), self);
+        self.loading.pop();
+        let (mut node, exports) = parsed?;
+
+        let prefix = self.new_prefix(&resolved);
+        qualify_module(&mut node, &prefix)?;
+
+        let module = Module { prefix, exports };
+        self.modules.insert(resolved, module.clone());
+        Ok((module, node))
+    }
+
+    /// Looks for `path` next to the importing file, then in the search paths
+    fn resolve(&self, loc: &Loc, path: &str) -> ParseResult<(String, PathBuf)> {
+        let importer_dir = Path::new(loc.path()).parent().unwrap_or(Path::new(""));
+        let dirs =
+            std::iter::once(importer_dir).chain(self.search_paths.iter().map(PathBuf::as_path));
+        for dir in dirs {
+            let candidate = dir.join(path);
+            if candidate.is_file() {
+                if let Ok(resolved) = candidate.canonicalize() {
+                    return Ok((candidate.display().to_string(), resolved));
+                }
+            }
+        }
+        Err(Error(
+            loc.clone(),
+            format!("Cannot find imported file {path}"),
+        ))
+    }
+
+    /// Makes a prefix out of the file name that no other module uses
+    fn new_prefix(&mut self, path: &Path) -> String {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let mut base: String = stem
+            .chars()
+            .map(|ch| if ch.is_ascii_alphanumeric() { ch } else { '_' })
+            .collect();
+        if !base.starts_with(|ch: char| ch.is_ascii_alphabetic()) {
+            base.insert(0, '_');
+        }
+
+        let mut prefix = base.clone();
+        let mut n = 1;
+        while self.prefixes.contains(&prefix) {
+            n += 1;
+            prefix = format!("{base}{n}");
+        }
+        self.prefixes.insert(prefix.clone());
+        prefix
+    }
+}
+
+/// Functions and types defined by a module
+#[derive(Default)]
+struct Names {
+    funcs: HashSet<String>,
+    types: HashSet<String>,
+}
+
+/// Renames everything a module defines to `prefix.name`, along with all
+/// uses. Imported files may only contain definitions.
+fn qualify_module(node: &mut Node, prefix: &str) -> ParseResult<()> {
+    let Node::Block(_, statements) = node else {
+        panic!("a file should parse into a block");
+    };
+
+    let mut names = Names::default();
+    for statement in statements.iter() {
+        match statement {
+            // Definitions from files this one imports, already renamed
+            Node::Block(..) | Node::Nop(_) => {}
+            Node::FuncDef { .. } | Node::StructDef { .. } | Node::EnumDef { .. } => {
+                collect_names(statement, &mut names)
+            }
+            // Externs keep their names, which belong to the linker
+            Node::ExternFun { .. } => {}
+            _ => {
+                return Err(Error(
+                    statement.loc().clone(),
+                    "Only definitions are allowed in imported files".into(),
+                ))
+            }
+        }
+    }
+
+    for statement in statements {
+        if !matches!(statement, Node::Block(..)) {
+            qualify(statement, prefix, &names);
+        }
+    }
+    Ok(())
+}
+
+fn collect_names(node: &Node, names: &mut Names) {
+    match node {
+        Node::Block(_, nodes) => {
+            for node in nodes {
+                collect_names(node, names);
+            }
+        }
+        Node::If {
+            then_branch,
+            else_branch,
+            ..
+        } => {
+            collect_names(then_branch, names);
+            if let Some(else_branch) = else_branch {
+                collect_names(else_branch, names);
+            }
+        }
+        Node::While { body, .. } => collect_names(body, names),
+        Node::Match { arms, default, .. } => {
+            for (_, _, body) in arms {
+                collect_names(body, names);
+            }
+            if let Some(default) = default {
+                collect_names(default, names);
+            }
+        }
+        Node::FuncDef {
+            name, body, export, ..
+        } => {
+            // Exported functions keep their names, which belong to the linker
+            if !export {
+                names.funcs.insert(name.clone());
+            }
+            collect_names(body, names);
+        }
+        Node::StructDef { name, .. } | Node::EnumDef { name, .. } => {
+            names.types.insert(name.clone());
+        }
+        _ => {}
+    }
+}
+
+fn rename(name: &mut String, prefix: &str, defined: &HashSet<String>) {
+    if defined.contains(name) {
+        *name = format!("{prefix}.{name}");
+    }
+}
+
+fn qualify_type(ty: &mut Type, prefix: &str, names: &Names) {
+    match ty {
+        Type::Struct(name) | Type::Enum(name) => rename(name, prefix, &names.types),
+        Type::Ptr(ty) | Type::Array(ty, _) => qualify_type(ty, prefix, names),
+        Type::Fun(params, ret) => {
+            for param in params {
+                qualify_type(param, prefix, names);
+            }
+            qualify_type(ret, prefix, names);
+        }
+        _ => {}
+    }
+}
+
+fn qualify_sig(
+    args: &mut [(String, Option<Type>)],
+    ret: &mut Option<Type>,
+    prefix: &str,
+    names: &Names,
+) {
+    for ty in args.iter_mut().filter_map(|(_, ty)| ty.as_mut()) {
+        qualify_type(ty, prefix, names);
+    }
+    if let Some(ret) = ret {
+        qualify_type(ret, prefix, names);
+    }
+}
+
+fn qualify(node: &mut Node, prefix: &str, names: &Names) {
+    match node {
+        Node::FuncCall(_, name, args) => {
+            rename(name, prefix, &names.funcs);
+            for arg in args {
+                qualify(arg, prefix, names);
+            }
+        }
+        Node::VarAddr(_, name) | Node::FuncAddr(_, name) => rename(name, prefix, &names.funcs),
+        Node::FuncDef {
+            name,
+            args,
+            ret,
+            body,
+            ..
+        } => {
+            rename(name, prefix, &names.funcs);
+            qualify_sig(args, ret, prefix, names);
+            qualify(body, prefix, names);
+        }
+        Node::ExternFun { args, ret, .. } => qualify_sig(args, ret, prefix, names),
+        Node::StructDef { name, fields, .. } => {
+            rename(name, prefix, &names.types);
+            for (_, ty) in fields {
+                qualify_type(ty, prefix, names);
+            }
+        }
+        Node::EnumDef { name, .. } | Node::EnumConst(_, name, _) => {
+            rename(name, prefix, &names.types)
+        }
+        Node::VarDecl(_, _, ty, value) => {
+            if let Some(ty) = ty {
+                qualify_type(ty, prefix, names);
+            }
+            qualify(value, prefix, names);
+        }
+        Node::SizeOf(_, ty, _) => qualify_type(ty, prefix, names),
+        Node::Cast(_, ty, value) => {
+            qualify_type(ty, prefix, names);
+            qualify(value, prefix, names);
+        }
+        Node::Block(_, nodes) | Node::ArrayLit { elems: nodes, .. } | Node::Syscall(_, nodes) => {
+            for node in nodes {
+                qualify(node, prefix, names);
+            }
+        }
+        Node::IndirectCall(_, callee, args) => {
+            qualify(callee, prefix, names);
+            for arg in args {
+                qualify(arg, prefix, names);
+            }
+        }
+        Node::If {
+            cond,
+            then_branch,
+            else_branch,
+            ..
+        } => {
+            qualify(cond, prefix, names);
+            qualify(then_branch, prefix, names);
+            if let Some(else_branch) = else_branch {
+                qualify(else_branch, prefix, names);
+            }
+        }
+        Node::While { cond, body, .. } => {
+            qualify(cond, prefix, names);
+            qualify(body, prefix, names);
+        }
+        Node::Match {
+            value,
+            arms,
+            default,
+            ..
+        } => {
+            qualify(value, prefix, names);
+            for (_, _, body) in arms {
+                qualify(body, prefix, names);
+            }
+            if let Some(default) = default {
+                qualify(default, prefix, names);
+            }
+        }
+        Node::BinOp(_, _, a, b)
+        | Node::CmpOp(_, _, a, b)
+        | Node::PtrAssign(_, a, b, _)
+        | Node::FieldAssign(_, a, b)
+        | Node::IndexAssign(_, a, b)
+        | Node::Index {
+            base: a, index: b, ..
+        } => {
+            qualify(a, prefix, names);
+            qualify(b, prefix, names);
+        }
+        Node::VarAssign(_, _, value)
+        | Node::PtrAccess(_, value, _)
+        | Node::Return(_, value)
+        | Node::Exit(_, value)
+        | Node::FieldAccess(_, value, ..) => qualify(value, prefix, names),
+        Node::StrLit(..)
+        | Node::VarAccess(..)
+        | Node::Int(..)
+        | Node::Nop(_)
+        | Node::Buf(..)
+        | Node::Break(_)
+        | Node::Asm(..) => {}
+    }
+}
@@ -2,7 +2,7 @@ use std::{collections::HashMap, fmt::Display};
 
 use crate::{
     loc::Loc,
-    parser::{BinOp, CmpOp, Node},
+    parser::{self, BinOp, CmpOp, Node, Type},
 };
 
 pub struct Program {
@@ -10,35 +10,78 @@ pub struct Program {
     pub scopes: HashMap<String, Vec<String>>,
     pub label_count: usize,
     pub fn_bodies: HashMap<String, Vec<Instruction>>,
+    pub temps: HashMap<String, usize>,
+    pub local_bufs: HashMap<String, usize>,
     pub bufs: Vec<usize>,
+    /// Functions the linker has to find elsewhere
+    pub externs: Vec<String>,
+    /// Functions made visible to the linker, with their return types
+    pub exports: Vec<(String, Type)>,
     backpatch_stack: Vec<usize>,
+    /// Jumps to backpatch with the end of each enclosing loop
+    break_stack: Vec<Vec<usize>>,
+    temp_count: usize,
+    pub bounds_check: bool,
+    /// Top-level code becomes `main`, called by the C runtime, instead of `_start`
+    pub libc: bool,
+    /// Bytes of stack taken by buffers of the function being lowered, `None`
+    /// at top level where buffers go to .bss
+    local_buf_size: Option<usize>,
 }
 
-#[derive(Debug)]
+/// An operand of an instruction. Values never contain other values: anything
+/// that needs computing is lowered into an instruction writing a temporary.
+#[derive(Debug, Clone)]
 pub enum Value {
     Void,
     String(usize),
     FromVar(usize),
     Int(usize),
-    BinOp(BinOp, Box<Value>, Box<Value>),
-    CmpOp(CmpOp, Box<Value>, Box<Value>),
     Buf(usize),
-    PtrAccess(Box<Value>),
+    /// Offset of the buffer's start below the function's buffer area
+    LocalBuf(usize),
     VarAddr(usize),
-    FuncCall(String, Vec<Value>),
+    Temp(usize),
+    FuncAddr(String),
+}
+
+/// A piece of an `asm` block, with placeholders resolved to variables
+#[derive(Debug)]
+pub enum AsmPart {
+    Text(String),
+    Var(usize),
 }
 
 #[derive(Debug)]
 pub enum Instruction {
+    /// Marks the start of a statement, for diagnostics
+    Loc(Loc),
     VarAssign(usize, Value),
+    BinOp(usize, BinOp, Value, Value),
+    CmpOp(usize, CmpOp, Value, Value),
+    PtrAccess(usize, Value, usize),
+    FuncCall(usize, String, Vec<Value>),
+    /// Calls the function the first value points to
+    IndirectCall(usize, Value, Vec<Value>),
+    /// Makes the system call numbered by the first value
+    Syscall(usize, Vec<Value>),
     Label(usize),
     JmpIfZero(Value, usize),
     Jmp(usize),
     Prologue(usize),
     Return(Value),
-    Exit(u8),
-    PtrAssign(Value, Value),
-    EvalValue(Value),
+    /// Ends the process with the value as its status
+    Exit(Value),
+    PtrAssign(Value, Value, usize),
+    /// Exits with the error message string unless the index is below the length
+    BoundsCheck(Value, usize, usize),
+    /// Zeroes the given number of bytes
+    Zero(Value, usize),
+    /// Jumps to the label of the inclusive `(low, high, label)` range holding
+    /// the value, or to the last label if there's none
+    Switch(Value, Vec<(usize, usize, usize)>, usize),
+    /// Inline assembly, and the registers it clobbers
+    Asm(Vec<AsmPart>, Vec<String>),
 }
 
 impl Instruction {
@@ -56,7 +99,7 @@ impl Instruction {
 }
 
 #[derive(Debug)]
-pub struct IRError(Loc, String);
+pub struct IRError(pub Loc, pub String);
 
 impl Display for IRError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -83,7 +126,7 @@ impl Program {
 
         println!("Code: ");
         for (fun, code) in &self.fn_bodies {
-            println!("fun {}():", fun);
+            println!("fun {}() ({} temps):", fun, self.temps[fun]);
             for inst in code {
                 println!("  {inst:?}");
             }
@@ -92,7 +135,14 @@ impl Program {
         println!("END DISASSEMBLY");
     }
 
-    pub fn from_ast(node: &Node) -> Result<Program, IRError> {
+    /// Lowers a checked program. Libraries get no entry point, so they may
+    /// only contain definitions.
+    pub fn from_ast(
+        node: &Node,
+        bounds_check: bool,
+        libc: bool,
+        lib: bool,
+    ) -> Result<Program, IRError> {
         let strings = Vec::new();
         let mut code = Vec::new();
         let mut vars = Vec::new();
@@ -100,17 +150,62 @@ impl Program {
             strings,
             fn_bodies: HashMap::new(),
             scopes: HashMap::new(),
+            temps: HashMap::new(),
+            local_bufs: HashMap::new(),
             label_count: 0,
             bufs: Vec::new(),
+            externs: Vec::new(),
+            exports: Vec::new(),
             backpatch_stack: Vec::new(),
+            break_stack: Vec::new(),
+            temp_count: 0,
+            bounds_check,
+            libc,
+            local_buf_size: None,
         };
 
+        if lib {
+            let Node::Block(_, statements) = node else {
+                panic!("a file should parse into a block");
+            };
+            for statement in statements {
+                match statement {
+                    // Imported files only contain definitions
+                    Node::Block(..)
+                    | Node::Nop(_)
+                    | Node::FuncDef { .. }
+                    | Node::ExternFun { .. }
+                    | Node::StructDef { .. }
+                    | Node::EnumDef { .. } => {}
+                    _ => {
+                        return Err(IRError(
+                            statement.loc().clone(),
+                            "Only definitions are allowed at the top level of a library".into(),
+                        ))
+                    }
+                }
+            }
+            prog.visit(node, &mut vars, &mut code)?;
+            // The runtime defines functions the prelude declares
+            prog.externs
+                .retain(|name| !prog.fn_bodies.contains_key(name));
+            return Ok(prog);
+        }
+
         code.push(Instruction::Prologue(0));
         prog.visit(node, &mut vars, &mut code)?;
-        code.push(Instruction::Exit(0));
+        code.push(Instruction::Exit(Value::Int(0)));
+        // Returning from the top level ends the program with that status
+        for inst in &mut code {
+            if let Instruction::Return(val) = inst {
+                *inst = Instruction::Exit(std::mem::replace(val, Value::Void));
+            }
+        }
 
         prog.fn_bodies.insert("_start".into(), code);
         prog.scopes.insert("_start".into(), vars);
+        prog.temps.insert("_start".into(), prog.temp_count);
+        prog.local_bufs.insert("_start".into(), 0);
 
         Ok(prog)
     }
@@ -128,24 +223,65 @@ impl Program {
                     .map(|arg| self.visit(arg, scope, code))
                     .collect();
 
-                Value::FuncCall(name.clone(), args?)
+                let temp = self.new_temp();
+                code.push(Instruction::FuncCall(temp, name.clone(), args?));
+                Value::Temp(temp)
             }
-            Node::StrLit(_, string) => {
-                if let Some(idx) = self.strings.iter().position(|x| x == string) {
-                    Value::String(idx)
-                } else {
-                    self.strings.push(string.clone());
-                    Value::String(self.strings.len() - 1)
+            Node::IndirectCall(_, callee, args) => {
+                let callee = self.visit(callee, scope, code)?;
+                let args: Result<Vec<_>, _> = args
+                    .iter()
+                    .map(|arg| self.visit(arg, scope, code))
+                    .collect();
+
+                let temp = self.new_temp();
+                code.push(Instruction::IndirectCall(temp, callee, args?));
+                Value::Temp(temp)
+            }
+            Node::Syscall(_, args) => {
+                let args: Result<Vec<_>, _> = args
+                    .iter()
+                    .map(|arg| self.visit(arg, scope, code))
+                    .collect();
+
+                let temp = self.new_temp();
+                code.push(Instruction::Syscall(temp, args?));
+                Value::Temp(temp)
+            }
+            Node::Exit(_, val) => {
+                let val = self.visit(val, scope, code)?;
+                code.push(Instruction::Exit(val));
+                Value::Void
+            }
+            Node::FuncAddr(_, name) => Value::FuncAddr(name.clone()),
+            Node::Asm(loc, parts, clobbers) => {
+                let mut resolved = Vec::new();
+                for part in parts {
+                    resolved.push(match part {
+                        parser::AsmPart::Text(text) => AsmPart::Text(text.clone()),
+                        parser::AsmPart::Var(name) => match scope.iter().position(|x| x == name) {
+                            Some(id) => AsmPart::Var(id),
+                            None => {
+                                return Err(IRError(
+                                    loc.clone(),
+                                    format!("Undeclared variable: {name}"),
+                                ))
+                            }
+                        },
+                    });
                 }
+                code.push(Instruction::Asm(resolved, clobbers.clone()));
+                Value::Void
             }
+            Node::StrLit(_, string) => Value::String(self.add_string(string)),
             Node::Block(_, nodes) => {
                 for node in nodes {
-                    let val = self.visit(node, scope, code)?;
-                    code.push(Instruction::EvalValue(val));
+                    code.push(Instruction::Loc(node.loc().clone()));
+                    self.visit(node, scope, code)?;
                 }
                 Value::Void
             }
-            Node::VarDecl(loc, name, node) => {
+            Node::VarDecl(loc, name, _, node) => {
                 if scope.contains(name) {
                     return Err(IRError(
                         loc.clone(),
@@ -179,18 +315,22 @@ impl Program {
                     ));
                 }
             }
-            &Node::Int(_, int) => Value::Int(int),
+            &Node::Int(_, int) | &Node::EnumConst(_, _, int) => Value::Int(int),
             Node::BinOp(_, op, a, b) => {
                 let a = self.visit(a, scope, code)?;
                 let b = self.visit(b, scope, code)?;
 
-                Value::BinOp(*op, Box::new(a), Box::new(b))
+                let temp = self.new_temp();
+                code.push(Instruction::BinOp(temp, *op, a, b));
+                Value::Temp(temp)
             }
             Node::CmpOp(_, op, a, b) => {
                 let a = self.visit(a, scope, code)?;
                 let b = self.visit(b, scope, code)?;
 
-                Value::CmpOp(*op, Box::new(a), Box::new(b))
+                let temp = self.new_temp();
+                code.push(Instruction::CmpOp(temp, *op, a, b));
+                Value::Temp(temp)
             }
             Node::If {
                 loc: _,
@@ -227,51 +367,94 @@ impl Program {
                 self.backpatch_stack.push(code.len());
                 code.push(Instruction::JmpIfZero(cond, 0));
 
+                self.break_stack.push(Vec::new());
                 self.visit(body, scope, code)?;
 
                 code.push(Instruction::Jmp(start_label));
 
                 self.backpatch(code);
+                let end_label = self.label_count - 1;
+                for i in self.break_stack.pop().unwrap() {
+                    code[i].backpatch(end_label);
+                }
 
                 Value::Void
             }
             Node::FuncDef {
-                loc: _,
+                loc,
                 name,
                 args,
+                ret,
                 body,
+                export,
             } => {
+                if *export {
+                    let ret = ret.clone().unwrap_or(Type::Int);
+                    self.exports.push((name.clone(), ret));
+                }
+                if self.libc && name == "main" {
+                    return Err(IRError(
+                        loc.clone(),
+                        "Cannot define main with --libc, top-level code becomes main".into(),
+                    ));
+                }
                 let mut body_code = Vec::new();
                 let mut body_vars = Vec::new();
+                let outer_temps = std::mem::replace(&mut self.temp_count, 0);
+                let outer_bufs = self.local_buf_size.replace(0);
+                let outer_breaks = std::mem::take(&mut self.break_stack);
 
+                // The definition's location comes first so diagnostics about
+                // the whole function can point at it
+                body_code.push(Instruction::Loc(loc.clone()));
                 body_code.push(Instruction::Prologue(args.len()));
-                for arg in args {
+                for (arg, _) in args {
                     body_vars.push(arg.clone());
                 }
+                // C only sets the low bits of narrow arguments, so exported
+                // functions clear the rest by reloading them at their size
+                if *export {
+                    for (i, (_, ty)) in args.iter().enumerate() {
+                        let size = match ty {
+                            Some(Type::Byte | Type::Bool) => 1,
+                            Some(Type::Word) => 2,
+                            Some(Type::Dword) => 4,
+                            _ => continue,
+                        };
+                        let temp = self.new_temp();
+                        body_code.push(Instruction::PtrAccess(temp, Value::VarAddr(i), size));
+                        body_code.push(Instruction::VarAssign(i, Value::Temp(temp)));
+                    }
+                }
 
                 self.visit(body, &mut body_vars, &mut body_code)?;
                 body_code.push(Instruction::Return(Value::Void));
 
                 self.fn_bodies.insert(name.clone(), body_code);
                 self.scopes.insert(name.clone(), body_vars);
+                self.temps.insert(name.clone(), self.temp_count);
+                self.local_bufs
+                    .insert(name.clone(), self.local_buf_size.unwrap());
+                self.temp_count = outer_temps;
+                self.local_buf_size = outer_bufs;
+                self.break_stack = outer_breaks;
                 Value::Void
             }
-            &Node::Buf(_, size) => {
-                self.bufs.push(size);
-                Value::Buf(self.bufs.len() - 1)
-            }
-            Node::PtrAssign(_, ptr, val) => {
+            &Node::Buf(_, size, is_static) => self.add_buf(size, is_static),
+            Node::PtrAssign(_, ptr, val, size) => {
                 let ptr = self.visit(ptr, scope, code)?;
                 let val = self.visit(val, scope, code)?;
 
-                code.push(Instruction::PtrAssign(ptr, val));
+                code.push(Instruction::PtrAssign(ptr, val, *size));
 
                 Value::Void
             }
-            Node::PtrAccess(_, ptr) => {
+            Node::PtrAccess(_, ptr, size) => {
                 let ptr = self.visit(ptr, scope, code)?;
-                Value::PtrAccess(Box::new(ptr))
-            },
+                let temp = self.new_temp();
+                code.push(Instruction::PtrAccess(temp, ptr, *size));
+                Value::Temp(temp)
+            }
             Node::VarAddr(loc, name) => {
                 if let Some(id) = scope.iter().position(|x| x == name) {
                     Value::VarAddr(id)
@@ -284,10 +467,193 @@ impl Program {
                 code.push(Instruction::Return(val));
                 Value::Void
             }
-            Node::Nop(_) => Value::Void,
+            Node::Break(loc) => {
+                let Some(breaks) = self.break_stack.last_mut() else {
+                    return Err(IRError(loc.clone(), "break outside of a loop".into()));
+                };
+                breaks.push(code.len());
+                code.push(Instruction::Jmp(0));
+                Value::Void
+            }
+            &Node::FieldAccess(_, ref ptr, _, offset, size) => {
+                let addr = self.visit_field_addr(ptr, offset, scope, code)?;
+                let temp = self.new_temp();
+                code.push(Instruction::PtrAccess(temp, addr, size));
+                Value::Temp(temp)
+            }
+            Node::FieldAssign(_, target, val) => {
+                let &Node::FieldAccess(_, ref ptr, _, offset, size) = &**target else {
+                    panic!("field assignment to {:?}", target);
+                };
+                let addr = self.visit_field_addr(ptr, offset, scope, code)?;
+                let val = self.visit(val, scope, code)?;
+                code.push(Instruction::PtrAssign(addr, val, size));
+                Value::Void
+            }
+            &Node::SizeOf(_, _, size) => Value::Int(size),
+            Node::Cast(_, _, value) => self.visit(value, scope, code)?,
+            Node::Index { .. } => {
+                let (addr, size) = self.visit_index_addr(node, scope, code)?;
+                let temp = self.new_temp();
+                code.push(Instruction::PtrAccess(temp, addr, size));
+                Value::Temp(temp)
+            }
+            Node::IndexAssign(_, target, val) => {
+                let (addr, size) = self.visit_index_addr(target, scope, code)?;
+                let val = self.visit(val, scope, code)?;
+                code.push(Instruction::PtrAssign(addr, val, size));
+                Value::Void
+            }
+            &Node::ArrayLit {
+                loc: _,
+                ref elems,
+                elem_size,
+                len,
+            } => {
+                let storage = self.add_buf(len * elem_size, false);
+                if elems.len() < len {
+                    let rest = self.offset(storage.clone(), elems.len() * elem_size, code);
+                    code.push(Instruction::Zero(rest, (len - elems.len()) * elem_size));
+                }
+                for (i, elem) in elems.iter().enumerate() {
+                    let val = self.visit(elem, scope, code)?;
+                    let addr = self.offset(storage.clone(), i * elem_size, code);
+                    code.push(Instruction::PtrAssign(addr, val, elem_size));
+                }
+                storage
+            }
+            Node::Match {
+                loc: _,
+                value,
+                arms,
+                default,
+            } => {
+                let value = self.visit(value, scope, code)?;
+                let switch = code.len();
+                code.push(Instruction::Switch(value.clone(), Vec::new(), 0));
+
+                let mut cases = Vec::new();
+                let mut ends = Vec::new();
+                for (_, patterns, body) in arms {
+                    let label = self.add_label(code);
+                    cases.extend(patterns.iter().map(|&(low, high)| (low, high, label)));
+                    self.visit(body, scope, code)?;
+                    ends.push(code.len());
+                    code.push(Instruction::Jmp(0));
+                }
+
+                let default_label = self.add_label(code);
+                if let Some(default) = default {
+                    self.visit(default, scope, code)?;
+                }
+                let end = self.add_label(code);
+                for i in ends {
+                    code[i].backpatch(end);
+                }
+                code[switch] = Instruction::Switch(value, cases, default_label);
+
+                Value::Void
+            }
+            Node::ExternFun { name, symbol, .. } => {
+                self.externs.push(symbol.as_ref().unwrap_or(name).clone());
+                Value::Void
+            }
+            Node::StructDef { .. } | Node::EnumDef { .. } | Node::Nop(_) => Value::Void,
         })
     }
 
+    fn visit_field_addr(
+        &mut self,
+        ptr: &Node,
+        offset: usize,
+        scope: &mut Vec<String>,
+        code: &mut Vec<Instruction>,
+    ) -> Result<Value, IRError> {
+        let ptr = self.visit(ptr, scope, code)?;
+        Ok(self.offset(ptr, offset, code))
+    }
+
+    /// Computes the address of an element and returns it along with the
+    /// element's size
+    fn visit_index_addr(
+        &mut self,
+        node: &Node,
+        scope: &mut Vec<String>,
+        code: &mut Vec<Instruction>,
+    ) -> Result<(Value, usize), IRError> {
+        let &Node::Index {
+            ref loc,
+            ref base,
+            ref index,
+            elem_size,
+            len,
+        } = node
+        else {
+            panic!("index assignment to {:?}", node);
+        };
+        let base = self.visit(base, scope, code)?;
+        let index = self.visit(index, scope, code)?;
+
+        if let (true, Some(len)) = (self.bounds_check, len) {
+            let msg = self.add_string(&format!("{loc}: Index out of bounds\n"));
+            code.push(Instruction::BoundsCheck(index.clone(), len, msg));
+        }
+
+        let offset = if elem_size == 1 {
+            index
+        } else {
+            let temp = self.new_temp();
+            code.push(Instruction::BinOp(
+                temp,
+                BinOp::Mult,
+                index,
+                Value::Int(elem_size),
+            ));
+            Value::Temp(temp)
+        };
+        let temp = self.new_temp();
+        code.push(Instruction::BinOp(temp, BinOp::Plus, base, offset));
+        Ok((Value::Temp(temp), elem_size))
+    }
+
+    /// Adds a constant offset to a pointer
+    fn offset(&mut self, ptr: Value, offset: usize, code: &mut Vec<Instruction>) -> Value {
+        if offset == 0 {
+            return ptr;
+        }
+        let temp = self.new_temp();
+        code.push(Instruction::BinOp(
+            temp,
+            BinOp::Plus,
+            ptr,
+            Value::Int(offset),
+        ));
+        Value::Temp(temp)
+    }
+
+    fn add_string(&mut self, string: &String) -> usize {
+        if let Some(idx) = self.strings.iter().position(|x| x == string) {
+            idx
+        } else {
+            self.strings.push(string.clone());
+            self.strings.len() - 1
+        }
+    }
+
+    fn add_buf(&mut self, size: usize, is_static: bool) -> Value {
+        match &mut self.local_buf_size {
+            Some(local_size) if !is_static => {
+                // Keep every buffer 8-byte aligned
+                *local_size += size.div_ceil(8) * 8;
+                Value::LocalBuf(*local_size)
+            }
+            _ => {
+                self.bufs.push(size);
+                Value::Buf(self.bufs.len() - 1)
+            }
+        }
+    }
+
     fn backpatch(&mut self, code: &mut Vec<Instruction>) {
         // TODO/TOFIGUREOUT: Maybe this isn't the best idea
         let i = self.backpatch_stack.pop().unwrap();
@@ -295,6 +661,11 @@ impl Program {
         code[i].backpatch(label);
     }
 
+    fn new_temp(&mut self) -> usize {
+        self.temp_count += 1;
+        self.temp_count - 1
+    }
+
     fn add_label(&mut self, code: &mut Vec<Instruction>) -> usize {
         code.push(Instruction::Label(self.label_count));
         self.label_count += 1;
@@ -15,6 +15,8 @@ pub enum TokenData {
     RParen,
     StrLit(String),
     Semicolon,
+    Colon,
+    Dot,
     Let,
     Equals,
     Int(usize),
@@ -30,15 +32,32 @@ pub enum TokenData {
     If,
     LCurly,
     RCurly,
+    LBracket,
+    RBracket,
     Else,
     Unless,
     While,
     Comma,
     Fun,
     Buf,
+    Static,
     Bang,
     Amp,
-    Return
+    Return,
+    Break,
+    Struct,
+    Sizeof,
+    Const,
+    Enum,
+    Match,
+    FatArrow,
+    DotDot,
+    Import,
+    As,
+    Asm,
+    Extern,
+    Ellipsis,
+    Export,
 }
 
 pub struct Lexer<T: Iterator<Item = char>> {
@@ -46,13 +65,27 @@ pub struct Lexer<T: Iterator<Item = char>> {
     code: Peekable<T>,
 }
 
-impl<'a, T: Iterator<Item = char>> Lexer<T> {
-    pub fn from_iter(path: &String, iter: T) -> Self {
+impl<T: Iterator<Item = char>> Lexer<T> {
+    pub fn from_iter(path: &str, iter: T) -> Self {
         Self {
             loc: Loc::new(path),
             code: iter.peekable(),
         }
     }
+
+    /// Reads the rest of an escape sequence after its backslash, leaving
+    /// anything that doesn't make one unread
+    fn escape(&mut self) -> Option<char> {
+        let ch = match self.code.peek()? {
+            'n' => '\n',
+            't' => '\t',
+            '0' => '\0',
+            &ch @ ('\\' | '\'' | '"') => ch,
+            _ => return None,
+        };
+        self.loc.advance(self.code.next().unwrap());
+        Some(ch)
+    }
 }
 
 fn keyword_or_name(name: &str) -> TokenData {
@@ -64,7 +97,19 @@ fn keyword_or_name(name: &str) -> TokenData {
         "while" => TokenData::While,
         "fun" => TokenData::Fun,
         "buf" => TokenData::Buf,
+        "static" => TokenData::Static,
         "return" => TokenData::Return,
+        "break" => TokenData::Break,
+        "struct" => TokenData::Struct,
+        "sizeof" => TokenData::Sizeof,
+        "const" => TokenData::Const,
+        "enum" => TokenData::Enum,
+        "match" => TokenData::Match,
+        "import" => TokenData::Import,
+        "as" => TokenData::As,
+        "asm" => TokenData::Asm,
+        "extern" => TokenData::Extern,
+        "export" => TokenData::Export,
         name => TokenData::Name(name.into()),
     }
 }
@@ -78,11 +123,27 @@ impl<T: Iterator<Item = char>> Iterator for Lexer<T> {
                 '(' => TokenData::LParen,
                 ')' => TokenData::RParen,
                 ';' => TokenData::Semicolon,
+                ':' => TokenData::Colon,
+                '.' => {
+                    if let Some('.') = self.code.peek() {
+                        self.loc.advance(self.code.next().unwrap());
+                        if let Some('.') = self.code.peek() {
+                            self.loc.advance(self.code.next().unwrap());
+                            TokenData::Ellipsis
+                        } else {
+                            TokenData::DotDot
+                        }
+                    } else {
+                        TokenData::Dot
+                    }
+                }
                 '+' => TokenData::Plus,
                 '-' => TokenData::Minus,
                 '*' => TokenData::Mult,
                 '{' => TokenData::LCurly,
                 '}' => TokenData::RCurly,
+                '[' => TokenData::LBracket,
+                ']' => TokenData::RBracket,
                 ',' => TokenData::Comma,
                 '!' => TokenData::Bang,
                 '&' => TokenData::Amp,
@@ -90,6 +151,9 @@ impl<T: Iterator<Item = char>> Iterator for Lexer<T> {
                     if let Some('=') = self.code.peek() {
                         self.loc.advance(self.code.next().unwrap());
                         TokenData::EqEq
+                    } else if let Some('>') = self.code.peek() {
+                        self.loc.advance(self.code.next().unwrap());
+                        TokenData::FatArrow
                     } else {
                         TokenData::Equals
                     }
@@ -135,24 +199,44 @@ impl<T: Iterator<Item = char>> Iterator for Lexer<T> {
                     keyword_or_name(&name)
                 }
                 '"' => {
+                    let loc = self.loc.clone();
                     let mut string = String::new();
 
-                    while let Some(ch) = self.code.next() {
-                        self.loc.advance(ch);
-                        if ch == '"' {
-                            break;
+                    loop {
+                        match self.code.next().map(|ch| self.loc.advance(ch)) {
+                            Some('"') => break,
+                            // So `printf` formats can end lines. Other
+                            // backslashes are kept as they were written.
+                            Some('\\') => string.push(self.escape().unwrap_or('\\')),
+                            Some(ch) => string.push(ch),
+                            None => return Some(Err((loc, "Unterminated string literal".into()))),
                         }
-                        string.push(ch);
                     }
 
                     TokenData::StrLit(string)
                 }
-                ch if ch.is_digit(10) => {
+                '\'' => {
+                    let loc = self.loc.clone();
+                    let ch = match self.code.next().map(|ch| self.loc.advance(ch)) {
+                        Some('\\') => match self.escape() {
+                            Some(ch) => ch,
+                            None => return Some(Err((loc, "Invalid escape sequence".into()))),
+                        },
+                        Some(ch) if ch.is_ascii() => ch,
+                        _ => return Some(Err((loc, "Expected ASCII character".into()))),
+                    };
+                    if self.code.next().map(|ch| self.loc.advance(ch)) != Some('\'') {
+                        return Some(Err((loc, "Unterminated character literal".into())));
+                    }
+
+                    TokenData::Int(ch as usize)
+                }
+                ch if ch.is_ascii_digit() => {
                     let mut number = String::new();
 
                     number.push(ch);
                     while let Some(ch) = self.code.peek() {
-                        if !ch.is_digit(10) {
+                        if !ch.is_ascii_digit() {
                             break;
                         }
                         number.push(
@@ -165,12 +249,7 @@ impl<T: Iterator<Item = char>> Iterator for Lexer<T> {
                 }
                 ch if ch.is_whitespace() => return self.next(), // ignore
 
-                ch => {
-                    return Some(Err((
-                        self.loc.clone(),
-                        format!("Invalid char: {ch}").into(),
-                    )))
-                }
+                ch => return Some(Err((self.loc.clone(), format!("Invalid char: {ch}")))),
             },
         }))
     }
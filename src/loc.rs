@@ -1,8 +1,8 @@
 use std::fmt::{Debug, Display, Formatter};
 
-#[derive(Clone, PartialEq)]
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Loc {
-    path: Box<String>,
+    path: String,
     line: usize,
     col: usize,
     char: usize,
@@ -21,15 +21,19 @@ impl Display for Loc {
 }
 
 impl Loc {
-    pub fn new(path: &String) -> Self {
+    pub fn new(path: &str) -> Self {
         Self {
-            path: Box::new(path.clone()),
+            path: path.into(),
             line: 1,
             col: 1,
             char: 0,
         }
     }
 
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
     // FIXME: line reporting is off on the first character of a line
     pub fn advance(&mut self, ch: char) -> char {
         self.char += 1;
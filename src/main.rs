@@ -1,22 +1,27 @@
 mod codegen;
+mod flow;
+mod header;
+mod import;
 mod ir;
 mod lexer;
 mod loc;
 mod parser;
+mod typecheck;
 
 //use shell_quote::{Bash, QuoteRefExt};
 use std::{
     env::{self, Args},
-    fs::{read_dir, DirEntry, File},
+    fs::{self, read_dir, remove_file, DirEntry, File},
     io::{Error, Read},
     os::unix::process::ExitStatusExt,
+    path::{Path, PathBuf},
     process::{exit, Command, ExitStatus},
 };
 
 use codegen::x86_64::Compile;
+use import::Loader;
 use ir::Program;
-use lexer::Lexer;
-use parser::parse;
+use typecheck::RuntimeSigs;
 
 use crate::parser::ParseError;
 
@@ -39,7 +44,7 @@ fn run_cmd(cmd: &[String]) -> std::io::Result<ExitStatus> {
         };
         print!("{} ", quoted);
     }
-    print!("\n");
+    println!();
 
     let mut command = Command::new(cmd[0].clone());
     for arg in &cmd[1..] {
@@ -49,18 +54,55 @@ fn run_cmd(cmd: &[String]) -> std::io::Result<ExitStatus> {
     process.wait()
 }
 
-fn parse_debug_flag(args: &mut std::iter::Peekable<env::Args>) -> bool {
-    match args.peek() {
-        Some(s) if s == "--debug" => {
-            args.next();
-            true
+struct Flags {
+    debug: bool,
+    bounds_check: bool,
+    /// Link against the C library, with `main` as the entry point
+    libc: bool,
+    /// Build a static archive and C header instead of an executable
+    lib: bool,
+    search_paths: Vec<PathBuf>,
+    /// Extra object files to link with
+    objects: Vec<String>,
+}
+
+fn parse_flags(args: &mut std::iter::Peekable<env::Args>) -> Flags {
+    let mut flags = Flags {
+        debug: false,
+        bounds_check: false,
+        libc: false,
+        lib: false,
+        search_paths: Vec::new(),
+        objects: Vec::new(),
+    };
+    while let Some(arg) = args.peek() {
+        match arg.as_str() {
+            "--debug" => flags.debug = true,
+            "--bounds-check" => flags.bounds_check = true,
+            "--libc" => flags.libc = true,
+            "--lib" => flags.lib = true,
+            "-I" => {
+                args.next();
+                let path = args.peek().expect("Please provide a search path after -I");
+                flags.search_paths.push(path.into());
+            }
+            "--link" => {
+                args.next();
+                let path = args
+                    .peek()
+                    .expect("Please provide an object file after --link");
+                flags.objects.push(path.clone());
+            }
+            _ => break,
         }
-        _ => false,
+        args.next();
     }
+    flags
 }
 
-fn parse_and_report_err(lexer: Lexer<std::str::Chars<'_>>, debug: bool) -> parser::Node {
-    parse(lexer, debug)
+fn parse_and_report_err(loader: &mut Loader, path: &str, code: &str) -> parser::Node {
+    loader
+        .parse_main(path, code)
         .inspect_err(|err| match err {
             ParseError::Error(loc, err) => {
                 eprintln!("{}: {}", loc, err);
@@ -75,8 +117,8 @@ fn parse_and_report_err(lexer: Lexer<std::str::Chars<'_>>, debug: bool) -> parse
         .unwrap()
 }
 
-fn generate_ir_and_report_err(ast: parser::Node) -> Program {
-    Program::from_ast(&ast)
+fn check_types_and_report_err(ast: &mut parser::Node) {
+    typecheck::check(ast)
         .inspect_err(|err| {
             eprintln!("{}", err);
             exit(1);
@@ -84,56 +126,154 @@ fn generate_ir_and_report_err(ast: parser::Node) -> Program {
         .unwrap()
 }
 
-fn add_prefix(prefix: &'static str) -> impl FnMut(String) -> String {
-    move |x| format!("{prefix}{x}")
+fn generate_ir_and_report_err(
+    ast: parser::Node,
+    bounds_check: bool,
+    libc: bool,
+    lib: bool,
+) -> Program {
+    Program::from_ast(&ast, bounds_check, libc, lib)
+        .inspect_err(|err| {
+            eprintln!("{}", err);
+            exit(1);
+        })
+        .unwrap()
+}
+
+fn check_flow_and_report(prog: &Program) {
+    let warnings = flow::check(prog)
+        .inspect_err(|err| {
+            eprintln!("{}", err);
+            exit(1);
+        })
+        .unwrap();
+    for warning in warnings {
+        eprintln!("{}", warning);
+    }
 }
 
 fn remove_bs(bs: Result<DirEntry, Error>) -> String {
     bs.unwrap().file_name().into_string().unwrap()
 }
 
-fn compile_prog(prog: Program, binary_path: &String) -> Result<(), std::io::Error> {
+fn assemble(path: &str, object: &str) -> Result<(), std::io::Error> {
+    let code = run_cmd(&["fasm".into(), path.into(), object.into()])?;
+    if !code.success() {
+        eprintln!("[ERROR] fasm exited with code {}", code.into_raw());
+        exit(1);
+    }
+    Ok(())
+}
+
+/// The runtime is written in jacl too. Every file under std/ but the prelude
+/// gets compiled like a library, and the objects are linked into programs.
+fn compile_runtime(libc: bool) -> Result<Vec<String>, std::io::Error> {
+    let mut file_names: Vec<String> = read_dir("std/")?.map(remove_bs).collect();
+    file_names.sort();
+
+    let mut objects = Vec::new();
+    let mut sigs = RuntimeSigs::default();
+    for file_name in file_names {
+        let Some(stem) = file_name.strip_suffix(".jacl") else {
+            continue;
+        };
+        if stem == "prelude" {
+            continue;
+        }
+        let path = format!("std/{file_name}");
+        let code = read_file(&path)?;
+        let mut loader = Loader::new(Vec::new(), false);
+        let mut ast = parse_and_report_err(&mut loader, &path, &code);
+        check_types_and_report_err(&mut ast);
+        sigs.add(&ast);
+        let prog = generate_ir_and_report_err(ast, false, libc, true);
+        check_flow_and_report(&prog);
+
+        let asm_path = format!("out_{stem}.asm");
+        let object = format!("out_{stem}.o");
+        prog.compile_to_asm(&asm_path)?;
+        assemble(&asm_path, &object)?;
+        objects.push(object);
+    }
+    sigs.check()
+        .inspect_err(|err| {
+            eprintln!("{}", err);
+            exit(1);
+        })
+        .unwrap();
+    Ok(objects)
+}
+
+fn compile_prog(prog: Program, binary_path: &str, flags: &Flags) -> Result<(), std::io::Error> {
     //set_current_dir("./asm")?;
     prog.compile_to_asm("out.asm")?;
-    for file_name in read_dir("std/")?.map(remove_bs).map(add_prefix("std/")) {
-        if file_name.ends_with(".asm") {
-            let code = run_cmd(&["fasm".into(), file_name.into()])?;
-            if !code.success() {
-                eprintln!("[ERROR] fasm exited with code {}", code.into_raw());
-                exit(1);
-            }
-        }
+    // Archive members keep their file names, so a library's object is named
+    // after the library instead of clashing with other libraries' `out.o`
+    let object = if flags.lib {
+        format!("{}.o", binary_path.trim_end_matches(".a"))
+    } else {
+        "out.o".into()
+    };
+    assemble("out.asm", &object)?;
+    let std_objects = compile_runtime(flags.libc)?;
+
+    if flags.lib {
+        // The runtime gets an archive of its own next to the library, so a C
+        // program linking several jacl libraries gets a single copy of it
+        let runtime_path = Path::new(binary_path).with_file_name("libjacl.a");
+        archive(&runtime_path.to_string_lossy(), &std_objects)?;
+        println!(
+            "[INFO] Link with {} for the runtime",
+            runtime_path.display()
+        );
+
+        let mut objects = vec![object.clone()];
+        objects.extend(flags.objects.iter().cloned());
+        archive(binary_path, &objects)?;
+        return remove_file(object);
     }
-    let code = run_cmd(&["fasm".into(), "out.asm".into()])?;
+
+    // The C compiler driver knows where the C runtime and library live
+    let linker = if flags.libc { "cc" } else { "ld" };
+    let mut args = vec![linker.into(), object];
+    args.extend(std_objects);
+    args.extend(flags.objects.iter().cloned());
+    if flags.libc {
+        // Absolute addresses like `mov reg, label` need a fixed load address
+        args.push("-no-pie".into());
+        // fasm doesn't mark its objects as not needing an executable stack
+        args.push("-Wl,-z,noexecstack".into());
+    }
+    args.extend(["-o".into(), binary_path.into()]);
+    let code = run_cmd(&args[..])?;
     if !code.success() {
-        eprintln!("[ERROR] fasm exited with code {}", code.into_raw());
+        eprintln!("[ERROR] {linker} exited with code {}", code.into_raw());
         exit(1);
     }
+    Ok(())
+}
 
-    let mut args = vec!["ld".into(), "out.o".into()];
-    args.extend(
-        read_dir("std/")?
-            .map(remove_bs)
-            .map(add_prefix("std/"))
-            .filter(|x| x.ends_with(".o"))
-            .collect::<Vec<_>>(),
-    );
-    args.extend(["-o".into(), binary_path.clone()]);
+fn archive(path: &str, objects: &[String]) -> Result<(), std::io::Error> {
+    // ar adds to an existing archive instead of replacing it
+    let _ = remove_file(path);
+    let mut args = vec!["ar".into(), "rcs".into(), path.into()];
+    args.extend(objects.iter().cloned());
     let code = run_cmd(&args[..])?;
-    Ok(if !code.success() {
-        eprintln!("[ERROR] ld exited with code {}", code.into_raw());
+    if !code.success() {
+        eprintln!("[ERROR] ar exited with code {}", code.into_raw());
         exit(1);
-    })
+    }
+    Ok(())
 }
 
-fn get_binary_path(args: &mut std::iter::Peekable<Args>, input_path: &String) -> String {
+fn get_binary_path(args: &mut std::iter::Peekable<Args>, input_path: &str) -> String {
     match args.peek() {
         Some(s) if s == "-o" || s == "--out" => {
             args.next();
             args.next().expect("Please provide an output path")
         }
         _ => {
-            let leaf = input_path.split('/').last().unwrap();
+            let leaf = input_path.split('/').next_back().unwrap();
             if leaf.contains('.') {
                 leaf.split('.').next().unwrap().into()
             } else {
@@ -147,27 +287,38 @@ fn main() -> std::io::Result<()> {
     let mut args = env::args().peekable();
     let _program = args.next().expect("Program name");
 
-    let debug = parse_debug_flag(&mut args);
+    let flags = parse_flags(&mut args);
 
     let filename = args.next().expect("Please provide a program");
     let code = read_file(&filename)?;
 
-    let binary_path = get_binary_path(&mut args, &filename);
-
-    let lexer = Lexer::from_iter(&filename, code.chars());
+    let mut binary_path = get_binary_path(&mut args, &filename);
+    if flags.lib && !binary_path.ends_with(".a") {
+        binary_path.push_str(".a");
+    }
 
-    let ast = parse_and_report_err(lexer, debug);
-    if debug {
+    let mut loader = Loader::new(flags.search_paths.clone(), flags.debug);
+    let mut ast = parse_and_report_err(&mut loader, &filename, &code);
+    check_types_and_report_err(&mut ast);
+    if flags.debug {
         println!("{ast:#?}");
     }
 
-    let prog = generate_ir_and_report_err(ast);
+    if flags.lib {
+        let header_path = format!("{}.h", binary_path.trim_end_matches(".a"));
+        let leaf = header_path.split('/').next_back().unwrap();
+        fs::write(&header_path, header::generate(&ast, &header::guard(leaf)))?;
+        println!("[INFO] Wrote C header to {header_path}");
+    }
+
+    let prog = generate_ir_and_report_err(ast, flags.bounds_check, flags.libc, flags.lib);
+    check_flow_and_report(&prog);
 
-    if debug {
+    if flags.debug {
         prog.disassemble();
     }
 
-    compile_prog(prog, &binary_path)?;
+    compile_prog(prog, &binary_path, &flags)?;
 
     println!("[INFO] Success! Finished binary is at {binary_path}");
 
@@ -1,9 +1,33 @@
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+    path::Path,
+};
+
 use crate::{
+    import::{Loader, Module},
     lexer::{Token, TokenData},
     loc::Loc,
 };
 
 pub type NodeList = Vec<Node>;
+/// The inclusive `(low, high)` ranges of values an arm handles, and its body
+pub type MatchArm = (Loc, Vec<(usize, usize)>, Node);
+/// A parameter's name and its type, if annotated
+pub type Param = (String, Option<Type>);
+
+/// Registers an `asm` block may declare as clobbered. `rbp` and `rsp` hold
+/// the stack frame, so they have to be left alone.
+const CLOBBERABLE: [&str; 14] = [
+    "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15",
+];
+
+/// A piece of an `asm` block: literal text, or a `{variable}` placeholder
+#[derive(Debug)]
+pub enum AsmPart {
+    Text(String),
+    Var(String),
+}
 
 #[derive(Debug, Clone, Copy)]
 pub enum BinOp {
@@ -22,12 +46,56 @@ pub enum CmpOp {
     GtEq,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    Int,
+    Byte,
+    Word,
+    Dword,
+    Bool,
+    Str,
+    Void,
+    Ptr(Box<Type>),
+    Struct(String),
+    Array(Box<Type>, usize),
+    Enum(String),
+    /// Pointer to a function taking the given parameters
+    Fun(Vec<Type>, Box<Type>),
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Type::Int => f.write_str("int"),
+            Type::Byte => f.write_str("byte"),
+            Type::Word => f.write_str("word"),
+            Type::Dword => f.write_str("dword"),
+            Type::Bool => f.write_str("bool"),
+            Type::Str => f.write_str("str"),
+            Type::Void => f.write_str("void"),
+            Type::Ptr(to) => write!(f, "*{to}"),
+            Type::Struct(name) | Type::Enum(name) => f.write_str(name),
+            Type::Array(elem, len) => write!(f, "[{elem}; {len}]"),
+            Type::Fun(params, ret) => {
+                f.write_str("fun(")?;
+                for (i, param) in params.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{param}")?;
+                }
+                write!(f, "): {ret}")
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Node {
     FuncCall(Loc, String, NodeList),
     StrLit(Loc, String),
     Block(Loc, NodeList),
-    VarDecl(Loc, String, Box<Node>),
+    VarDecl(Loc, String, Option<Type>, Box<Node>),
     VarAccess(Loc, String),
     VarAssign(Loc, String, Box<Node>),
     Int(Loc, usize),
@@ -48,14 +116,142 @@ pub enum Node {
     FuncDef {
         loc: Loc,
         name: String,
-        args: Vec<String>,
+        args: Vec<(String, Option<Type>)>,
+        ret: Option<Type>,
         body: Box<Node>,
+        /// Visible to the linker under its own name, callable from C
+        export: bool,
     },
-    Buf(Loc, usize),
-    PtrAccess(Loc, Box<Node>),
-    PtrAssign(Loc, Box<Node>, Box<Node>),
+    /// Buffers inside functions live on the stack unless marked `static`
+    Buf(Loc, usize, bool),
+    /// The last field is the operand size in bytes, filled in by the type checker
+    PtrAccess(Loc, Box<Node>, usize),
+    PtrAssign(Loc, Box<Node>, Box<Node>, usize),
     VarAddr(Loc, String),
+    /// The value is a `Nop` for a bare `return;`
     Return(Loc, Box<Node>),
+    Break(Loc),
+    StructDef {
+        loc: Loc,
+        name: String,
+        fields: Vec<(String, Type)>,
+    },
+    /// The last two fields are the offset and size of the field, filled in by
+    /// the type checker
+    FieldAccess(Loc, Box<Node>, String, usize, usize),
+    /// The target is always a `FieldAccess`
+    FieldAssign(Loc, Box<Node>, Box<Node>),
+    /// The last field is the size, filled in by the type checker
+    SizeOf(Loc, Type, usize),
+    /// Reads a value as another type of the same size, without converting it
+    Cast(Loc, Type, Box<Node>),
+    /// `elem_size` and `len` are filled in by the type checker, `len` being
+    /// known only when indexing an array rather than a pointer
+    Index {
+        loc: Loc,
+        base: Box<Node>,
+        index: Box<Node>,
+        elem_size: usize,
+        len: Option<usize>,
+    },
+    /// The target is always an `Index`
+    IndexAssign(Loc, Box<Node>, Box<Node>),
+    /// `elem_size` and `len` (which may be more than the number of elements)
+    /// are filled in by the type checker
+    ArrayLit {
+        loc: Loc,
+        elems: NodeList,
+        elem_size: usize,
+        len: usize,
+    },
+    /// Variants are numbered by the parser
+    EnumDef {
+        loc: Loc,
+        name: String,
+        variants: Vec<(String, usize)>,
+    },
+    /// A variant of the named enum, with its value
+    EnumConst(Loc, String, usize),
+    Match {
+        loc: Loc,
+        value: Box<Node>,
+        arms: Vec<MatchArm>,
+        default: Option<Box<Node>>,
+    },
+    /// Address of a function, made by the type checker from a `VarAddr`
+    /// naming one
+    FuncAddr(Loc, String),
+    /// Call through a function pointer
+    IndirectCall(Loc, Box<Node>, NodeList),
+    /// A system call with its number and arguments, made by the type checker
+    /// from a call to `syscall`
+    Syscall(Loc, NodeList),
+    /// Ends the process with a status, made by the type checker from a call
+    /// to `exit`
+    Exit(Loc, Box<Node>),
+    /// Assembly spliced into the output, and the registers it clobbers
+    Asm(Loc, Vec<AsmPart>, Vec<String>),
+    /// A function defined outside of the program, resolved by the linker.
+    /// Variadic ones take any number of arguments after `args`.
+    ExternFun {
+        loc: Loc,
+        name: String,
+        args: Vec<(String, Option<Type>)>,
+        ret: Option<Type>,
+        variadic: bool,
+        /// The linker's name for it, when it differs from `name`
+        symbol: Option<String>,
+    },
+}
+
+impl Node {
+    fn const_value(&self) -> Option<usize> {
+        match self {
+            &Node::Int(_, value) | &Node::EnumConst(_, _, value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn loc(&self) -> &Loc {
+        match self {
+            Node::FuncCall(loc, ..)
+            | Node::StrLit(loc, _)
+            | Node::Block(loc, _)
+            | Node::VarDecl(loc, ..)
+            | Node::VarAccess(loc, _)
+            | Node::VarAssign(loc, ..)
+            | Node::Int(loc, _)
+            | Node::BinOp(loc, ..)
+            | Node::CmpOp(loc, ..)
+            | Node::If { loc, .. }
+            | Node::Nop(loc)
+            | Node::While { loc, .. }
+            | Node::FuncDef { loc, .. }
+            | Node::Buf(loc, ..)
+            | Node::PtrAccess(loc, ..)
+            | Node::PtrAssign(loc, ..)
+            | Node::VarAddr(loc, _)
+            | Node::Return(loc, _)
+            | Node::Break(loc)
+            | Node::StructDef { loc, .. }
+            | Node::FieldAccess(loc, ..)
+            | Node::FieldAssign(loc, ..)
+            | Node::SizeOf(loc, ..)
+            | Node::Cast(loc, ..)
+            | Node::Index { loc, .. }
+            | Node::IndexAssign(loc, ..)
+            | Node::ArrayLit { loc, .. }
+            | Node::EnumDef { loc, .. }
+            | Node::EnumConst(loc, ..)
+            | Node::Match { loc, .. }
+            | Node::FuncAddr(loc, _)
+            | Node::IndirectCall(loc, ..)
+            | Node::Syscall(loc, _)
+            | Node::Exit(loc, _)
+            | Node::Asm(loc, ..)
+            | Node::ExternFun { loc, .. } => loc,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -65,12 +261,20 @@ pub enum ParseError {
     BlockEnding,
 }
 
-type ParseResult<T> = Result<T, ParseError>;
+pub type ParseResult<T> = Result<T, ParseError>;
+
+/// The constants and enums a file declares at top level, for the files
+/// importing it
+#[derive(Clone, Default)]
+pub struct Exports {
+    pub consts: HashMap<String, usize>,
+    pub enums: HashMap<String, Vec<(String, usize)>>,
+}
 
 pub fn parse<T: Iterator<Item = Result<Token, (Loc, String)>>>(
     lexer: T,
-    debug: bool,
-) -> ParseResult<Node> {
+    loader: &mut Loader,
+) -> ParseResult<(Node, Exports)> {
     let mut tokens = Vec::new();
     for res in lexer {
         match res {
@@ -78,19 +282,38 @@ pub fn parse<T: Iterator<Item = Result<Token, (Loc, String)>>>(
             Err((loc, err)) => Err(ParseError::LexerError(loc, err))?,
         }
     }
-    if debug {
+    if loader.debug {
         println!("{:#?}", tokens);
     }
-    Parser {
+    let mut parser = Parser {
         lexer: tokens,
         i: 0,
-    }
-    .parse_block()
+        consts: loader.prelude.consts.clone(),
+        enums: loader.prelude.enums.clone(),
+        namespaces: HashMap::new(),
+        depth: 0,
+        loader,
+    };
+    let node = parser.parse_statements()?;
+    let exports = Exports {
+        consts: parser.consts,
+        enums: parser.enums,
+    };
+    Ok((node, exports))
 }
 
-struct Parser {
+struct Parser<'a> {
     lexer: Vec<Token>,
     i: usize,
+    /// Values of the `const`s in scope, substituted as they are parsed
+    consts: HashMap<String, usize>,
+    /// Variants of the enums in scope
+    enums: HashMap<String, Vec<(String, usize)>>,
+    /// Imported files, by the name they're accessed through
+    namespaces: HashMap<String, Module>,
+    /// Number of blocks around the statement being parsed
+    depth: usize,
+    loader: &'a mut Loader,
 }
 
 /*
@@ -105,8 +328,23 @@ macro_rules! expect_pattern {
 */
 
 use ParseError::Error;
-impl Parser {
+impl Parser<'_> {
     fn parse_block(&mut self) -> ParseResult<Node> {
+        let consts = self.consts.clone();
+        let enums = self.enums.clone();
+        let namespaces = self.namespaces.clone();
+        self.depth += 1;
+
+        let block = self.parse_statements();
+
+        self.depth -= 1;
+        self.consts = consts;
+        self.enums = enums;
+        self.namespaces = namespaces;
+        block
+    }
+
+    fn parse_statements(&mut self) -> ParseResult<Node> {
         let loc = self.loc();
         let mut statements = Vec::new();
 
@@ -128,34 +366,48 @@ impl Parser {
     fn parse_statement(&mut self) -> ParseResult<Node> {
         let loc = self.loc();
         let res = match self.peek().expect("On EOF we shouldn't be here") {
-            TokenData::Name(name) => {
+            TokenData::Name(name) if !self.namespaces.contains_key(name) => {
                 let name = name.clone();
                 self.nom();
 
-                match self.nom() {
+                match self.peek() {
                     Some(TokenData::LParen) => {
-                        let mut args = vec![self.parse_expr()?];
-                        while let Some(TokenData::Comma) = self.peek() {
-                            self.nom();
-                            args.push(self.parse_expr()?);
-                        }
-                        self.expect(TokenData::RParen)?;
+                        let args = self.parse_args()?;
                         Ok(Node::FuncCall(loc, name, args))
                     }
                     Some(TokenData::Equals) => {
+                        self.nom();
+                        self.check_not_const(&loc, &name)?;
                         let value = self.parse_expr()?;
                         Ok(Node::VarAssign(loc, name, Box::new(value)))
                     }
-                    _ => Err(Error(loc, "Expected '(' or '='".into())),
+                    Some(TokenData::Dot | TokenData::LBracket) => {
+                        let target = self.parse_postfix(Node::VarAccess(loc.clone(), name))?;
+                        if let Node::IndirectCall(..) = target {
+                            Ok(target)
+                        } else {
+                            self.expect(TokenData::Equals)?;
+                            let value = Box::new(self.parse_expr()?);
+                            match target {
+                                Node::FieldAccess(..) => {
+                                    Ok(Node::FieldAssign(loc, Box::new(target), value))
+                                }
+                                _ => Ok(Node::IndexAssign(loc, Box::new(target), value)),
+                            }
+                        }
+                    }
+                    _ => Err(Error(loc, "Expected '(', '=', '.' or '['".into())),
                 }
             }
             TokenData::Let => {
                 self.nom();
                 if let Some(TokenData::Name(name)) = self.nom() {
                     let name = name.clone();
+                    self.check_not_const(&loc, &name)?;
+                    let ty = self.parse_annotation()?;
                     self.expect(TokenData::Equals)?;
 
-                    Ok(Node::VarDecl(loc, name, Box::new(self.parse_expr()?)))
+                    Ok(Node::VarDecl(loc, name, ty, Box::new(self.parse_expr()?)))
                 } else {
                     Err(Error(loc, "Expected identifier".into()))
                 }
@@ -221,26 +473,118 @@ impl Parser {
                     body: Box::new(body),
                 });
             }
-            TokenData::Fun => {
+            TokenData::Fun => return self.parse_fun(loc, false),
+            TokenData::Export => {
+                self.nom();
+                if self.depth > 0 {
+                    return Err(Error(
+                        loc,
+                        "Only top-level functions can be exported".into(),
+                    ));
+                }
+                return self.parse_fun(loc, true);
+            }
+            TokenData::Struct => {
                 self.nom();
                 let name = self.parse_ident()?;
-                self.expect(TokenData::LParen)?;
+                self.expect(TokenData::LCurly)?;
 
-                let mut args = vec![self.parse_ident()?];
-                while let Some(TokenData::Comma) = self.peek() {
-                    self.nom();
-                    args.push(self.parse_ident()?);
+                let mut fields = Vec::new();
+                while let Some(TokenData::Name(_)) = self.peek() {
+                    let field = self.parse_ident()?;
+                    self.expect(TokenData::Colon)?;
+                    fields.push((field, self.parse_type()?));
+                    if let Some(TokenData::Comma) = self.peek() {
+                        self.nom();
+                    } else {
+                        break;
+                    }
                 }
-                self.expect(TokenData::RParen)?;
+                self.expect(TokenData::RCurly)?;
 
+                return Ok(Node::StructDef { loc, name, fields });
+            }
+            TokenData::Enum => {
+                self.nom();
+                let name = self.parse_ident()?;
+                if self.enums.contains_key(&name) {
+                    return Err(Error(loc, format!("Already defined enum: {name}")));
+                }
                 self.expect(TokenData::LCurly)?;
-                let body = self.parse_block()?;
 
-                return Ok(Node::FuncDef {
+                let mut variants: Vec<(String, usize)> = Vec::new();
+                let mut next = 0;
+                while let Some(TokenData::Name(_)) = self.peek() {
+                    let variant_loc = self.loc();
+                    let variant = self.parse_ident()?;
+                    if variants.iter().any(|(x, _)| *x == variant) {
+                        return Err(Error(
+                            variant_loc,
+                            format!("Already defined variant: {name}.{variant}"),
+                        ));
+                    }
+                    if let Some(TokenData::Equals) = self.peek() {
+                        self.nom();
+                        next = self.parse_const_expr()?;
+                    }
+                    variants.push((variant, next));
+                    next = next.wrapping_add(1);
+                    if let Some(TokenData::Comma) = self.peek() {
+                        self.nom();
+                    } else {
+                        break;
+                    }
+                }
+                self.expect(TokenData::RCurly)?;
+
+                self.enums.insert(name.clone(), variants.clone());
+                return Ok(Node::EnumDef {
                     loc,
                     name,
-                    args,
-                    body: Box::new(body),
+                    variants,
+                });
+            }
+            TokenData::Match => {
+                self.nom();
+                let value = self.parse_expr()?;
+                self.expect(TokenData::LCurly)?;
+
+                let mut arms = Vec::new();
+                let mut default = None;
+                loop {
+                    let arm_loc = self.loc();
+                    match self.peek() {
+                        Some(TokenData::RCurly) => {
+                            self.nom();
+                            break;
+                        }
+                        Some(TokenData::Else) => {
+                            self.nom();
+                            if default.is_some() {
+                                return Err(Error(arm_loc, "Match already has an else arm".into()));
+                            }
+                            self.expect(TokenData::FatArrow)?;
+                            self.expect(TokenData::LCurly)?;
+                            default = Some(Box::new(self.parse_block()?));
+                        }
+                        _ => {
+                            let mut patterns = vec![self.parse_pattern()?];
+                            while let Some(TokenData::Comma) = self.peek() {
+                                self.nom();
+                                patterns.push(self.parse_pattern()?);
+                            }
+                            self.expect(TokenData::FatArrow)?;
+                            self.expect(TokenData::LCurly)?;
+                            arms.push((arm_loc, patterns, self.parse_block()?));
+                        }
+                    }
+                }
+
+                return Ok(Node::Match {
+                    loc,
+                    value: Box::new(value),
+                    arms,
+                    default,
                 });
             }
             TokenData::Bang => {
@@ -250,17 +594,119 @@ impl Parser {
 
                 let expr = self.parse_expr()?;
 
-                Ok(Node::PtrAssign(loc, Box::new(ptr), Box::new(expr)))
+                Ok(Node::PtrAssign(loc, Box::new(ptr), Box::new(expr), 0))
             }
             TokenData::RCurly => {
                 self.nom(); // Here it would make sense not to nom() but I don't want to rewrite everything
                 Err(ParseError::BlockEnding)
-            },
+            }
             TokenData::Return => {
                 self.nom();
-                let expr = self.parse_expr()?;
+                // A bare `return;` leaves a void function early
+                let expr = if let Some(TokenData::Semicolon) = self.peek() {
+                    Node::Nop(loc.clone())
+                } else {
+                    self.parse_expr()?
+                };
                 Ok(Node::Return(loc, Box::new(expr)))
-            },
+            }
+            TokenData::Break => {
+                self.nom();
+                Ok(Node::Break(loc))
+            }
+            TokenData::Extern => {
+                self.nom();
+                let symbol = if let Some(TokenData::StrLit(symbol)) = self.peek() {
+                    let symbol = symbol.clone();
+                    self.nom();
+                    Some(symbol)
+                } else {
+                    None
+                };
+                self.expect(TokenData::Fun)?;
+                let name = self.parse_ident()?;
+                let (args, variadic) = self.parse_params()?;
+                let ret = self.parse_annotation()?;
+                Ok(Node::ExternFun {
+                    loc,
+                    name,
+                    args,
+                    ret,
+                    variadic,
+                    symbol,
+                })
+            }
+            TokenData::Asm => {
+                self.nom();
+                let mut clobbers = Vec::new();
+                if let Some(TokenData::LParen) = self.peek() {
+                    self.nom();
+                    while let Some(TokenData::Name(_)) = self.peek() {
+                        let reg_loc = self.loc();
+                        let reg = self.parse_ident()?;
+                        if !CLOBBERABLE.contains(&reg.as_str()) {
+                            return Err(Error(reg_loc, format!("Cannot clobber {reg}")));
+                        }
+                        clobbers.push(reg);
+                        if let Some(TokenData::Comma) = self.peek() {
+                            self.nom();
+                        } else {
+                            break;
+                        }
+                    }
+                    self.expect(TokenData::RParen)?;
+                }
+                self.expect(TokenData::LCurly)?;
+
+                let mut parts = Vec::new();
+                while let Some(TokenData::StrLit(line)) = self.peek() {
+                    let line = line.clone();
+                    let line_loc = self.loc();
+                    self.nom();
+                    self.parse_asm_line(&line_loc, &line, &mut parts)?;
+                }
+                self.expect(TokenData::RCurly)?;
+
+                return Ok(Node::Asm(loc, parts, clobbers));
+            }
+            TokenData::Import => {
+                self.nom();
+                if self.depth > 0 {
+                    return Err(Error(loc, "Imports are only allowed at top level".into()));
+                }
+                let Some(TokenData::StrLit(path)) = self.nom() else {
+                    return Err(Error(loc, "Expected path to import".into()));
+                };
+                let path = path.clone();
+                let namespace = if let Some(TokenData::As) = self.peek() {
+                    self.nom();
+                    self.parse_ident()?
+                } else {
+                    let stem = Path::new(&path).file_stem().unwrap_or_default();
+                    stem.to_string_lossy().into_owned()
+                };
+
+                let (module, defs) = self.loader.import(&loc, &path)?;
+                match self.namespaces.get(&namespace) {
+                    Some(other) if other.prefix != module.prefix => {
+                        return Err(Error(
+                            loc,
+                            format!("Already imported another file as {namespace}"),
+                        ));
+                    }
+                    _ => self.namespaces.insert(namespace, module),
+                };
+                Ok(defs)
+            }
+            TokenData::Const => {
+                self.nom();
+                let name = self.parse_ident()?;
+                self.check_not_const(&loc, &name)?;
+                self.expect(TokenData::Equals)?;
+                let value = self.parse_const_expr()?;
+                self.consts.insert(name, value);
+                Ok(Node::Nop(loc))
+            }
             _ => self.parse_expr(),
         }?;
         self.expect(TokenData::Semicolon)?;
@@ -275,6 +721,118 @@ impl Parser {
         }
     }
 
+    fn parse_fun(&mut self, loc: Loc, export: bool) -> ParseResult<Node> {
+        self.expect(TokenData::Fun)?;
+        let name = self.parse_ident()?;
+        let (args, variadic) = self.parse_params()?;
+        if variadic {
+            return Err(Error(loc, "Only extern functions can be variadic".into()));
+        }
+        let ret = self.parse_annotation()?;
+
+        self.expect(TokenData::LCurly)?;
+        let body = self.parse_block()?;
+
+        Ok(Node::FuncDef {
+            loc,
+            name,
+            args,
+            ret,
+            body: Box::new(body),
+            export,
+        })
+    }
+
+    /// Parses a parenthesized parameter list, which may end with `...`
+    fn parse_params(&mut self) -> ParseResult<(Vec<Param>, bool)> {
+        self.expect(TokenData::LParen)?;
+        let mut params = Vec::new();
+        let mut variadic = false;
+        while self.peek() != Some(&TokenData::RParen) {
+            if let Some(TokenData::Ellipsis) = self.peek() {
+                self.nom();
+                variadic = true;
+                break;
+            }
+            params.push(self.parse_param()?);
+            if let Some(TokenData::Comma) = self.peek() {
+                self.nom();
+            } else {
+                break;
+            }
+        }
+        self.expect(TokenData::RParen)?;
+        Ok((params, variadic))
+    }
+
+    fn parse_param(&mut self) -> ParseResult<(String, Option<Type>)> {
+        let loc = self.loc();
+        let name = self.parse_ident()?;
+        self.check_not_const(&loc, &name)?;
+        let ty = self.parse_annotation()?;
+        Ok((name, ty))
+    }
+
+    /// Parses an optional `: type` annotation
+    fn parse_annotation(&mut self) -> ParseResult<Option<Type>> {
+        if let Some(TokenData::Colon) = self.peek() {
+            self.nom();
+            Ok(Some(self.parse_type()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn parse_type(&mut self) -> ParseResult<Type> {
+        let loc = self.loc();
+        match self.nom() {
+            Some(TokenData::Mult) => Ok(Type::Ptr(Box::new(self.parse_type()?))),
+            Some(TokenData::Fun) => {
+                self.expect(TokenData::LParen)?;
+                let mut params = Vec::new();
+                while self.peek() != Some(&TokenData::RParen) {
+                    params.push(self.parse_type()?);
+                    if let Some(TokenData::Comma) = self.peek() {
+                        self.nom();
+                    } else {
+                        break;
+                    }
+                }
+                self.expect(TokenData::RParen)?;
+                let ret = self.parse_annotation()?.unwrap_or(Type::Int);
+                Ok(Type::Fun(params, Box::new(ret)))
+            }
+            Some(TokenData::LBracket) => {
+                let elem = self.parse_type()?;
+                self.expect(TokenData::Semicolon)?;
+                let len = self.parse_const_expr()?;
+                self.expect(TokenData::RBracket)?;
+                Ok(Type::Array(Box::new(elem), len))
+            }
+            Some(TokenData::Name(name)) => match name.clone().as_str() {
+                "int" => Ok(Type::Int),
+                "byte" => Ok(Type::Byte),
+                "word" => Ok(Type::Word),
+                "dword" => Ok(Type::Dword),
+                "bool" => Ok(Type::Bool),
+                "str" => Ok(Type::Str),
+                "void" => Ok(Type::Void),
+                name if self.enums.contains_key(name) => Ok(Type::Enum(name.into())),
+                name if self.namespaces.contains_key(name) => {
+                    let (module, item) = self.parse_qualified(name)?;
+                    let name = format!("{}.{item}", module.prefix);
+                    if module.exports.enums.contains_key(&item) {
+                        Ok(Type::Enum(name))
+                    } else {
+                        Ok(Type::Struct(name))
+                    }
+                }
+                name => Ok(Type::Struct(name.into())),
+            },
+            _ => Err(Error(loc, "Expected type".into())),
+        }
+    }
+
     fn parse_expr(&mut self) -> ParseResult<Node> {
         let loc = self.loc();
         match self
@@ -286,19 +844,32 @@ impl Parser {
                 let name = name.clone();
 
                 match self.peek() {
-                    Some(TokenData::LParen) => {
-                        self.nom();
-                        let mut args = vec![self.parse_expr()?];
-                        while let Some(TokenData::Comma) = self.peek() {
-                            self.nom();
-                            args.push(self.parse_expr()?);
+                    Some(TokenData::Dot) if self.enums.contains_key(&name) => {
+                        let variants = self.enums[&name].clone();
+                        self.parse_variant(loc, name, &variants)
+                    }
+                    Some(TokenData::Dot) if self.namespaces.contains_key(&name) => {
+                        let (module, item) = self.parse_qualified(&name)?;
+                        let name = format!("{}.{item}", module.prefix);
+                        if let Some(&value) = module.exports.consts.get(&item) {
+                            Ok(Node::Int(loc, value))
+                        } else if let Some(variants) = module.exports.enums.get(&item) {
+                            self.parse_variant(loc, name, variants)
+                        } else {
+                            let args = self.parse_args()?;
+                            self.parse_postfix(Node::FuncCall(loc, name, args))
                         }
-                        self.expect(TokenData::RParen)?;
-                        Ok(Node::FuncCall(loc, name, args))
                     }
-                    _ => Ok(Node::VarAccess(loc, name.clone())),
+                    Some(TokenData::LParen) => {
+                        let args = self.parse_args()?;
+                        self.parse_postfix(Node::FuncCall(loc, name, args))
+                    }
+                    _ => match self.consts.get(&name) {
+                        Some(&value) => Ok(Node::Int(loc, value)),
+                        None => self.parse_postfix(Node::VarAccess(loc, name.clone())),
+                    },
                 }
-            },
+            }
             TokenData::Int(int) => Ok(Node::Int(loc, *int)),
 
             TokenData::Plus => self.parse_bin_op(BinOp::Plus),
@@ -312,19 +883,46 @@ impl Parser {
             TokenData::LtEq => self.parse_cmp_op(CmpOp::LtEq),
             TokenData::GtEq => self.parse_cmp_op(CmpOp::GtEq),
 
-            TokenData::Buf => {
-                if let Some(TokenData::Int(size)) = self.nom() {
-                    Ok(Node::Buf(loc, *size))
-                } else {
-                    Err(Error(loc, "Expected integer literal".into()))
+            TokenData::LBracket => {
+                let mut elems = Vec::new();
+                while self.peek() != Some(&TokenData::RBracket) {
+                    elems.push(self.parse_expr()?);
+                    if let Some(TokenData::Comma) = self.peek() {
+                        self.nom();
+                    } else {
+                        break;
+                    }
                 }
+                self.expect(TokenData::RBracket)?;
+                Ok(Node::ArrayLit {
+                    loc,
+                    elems,
+                    elem_size: 0,
+                    len: 0,
+                })
+            }
+            TokenData::Sizeof => Ok(Node::SizeOf(loc, self.parse_type()?, 0)),
+            TokenData::As => {
+                let ty = self.parse_type()?;
+                let value = self.parse_expr()?;
+                Ok(Node::Cast(loc, ty, Box::new(value)))
+            }
+            TokenData::Buf => self.parse_buf(loc, false),
+            TokenData::Static => {
+                self.expect(TokenData::Buf)?;
+                self.parse_buf(loc, true)
             }
             TokenData::Bang => {
                 let expr = self.parse_expr()?;
-                Ok(Node::PtrAccess(loc, Box::new(expr)))
+                Ok(Node::PtrAccess(loc, Box::new(expr), 0))
             }
             TokenData::Amp => {
                 let name = self.parse_ident()?;
+                if self.namespaces.contains_key(&name) {
+                    let (module, item) = self.parse_qualified(&name)?;
+                    return Ok(Node::VarAddr(loc, format!("{}.{item}", module.prefix)));
+                }
+                self.check_not_const(&loc, &name)?;
                 Ok(Node::VarAddr(loc, name))
             }
 
@@ -332,11 +930,171 @@ impl Parser {
         }
     }
 
+    /// Parses the `.item` following the name of an imported file
+    fn parse_qualified(&mut self, namespace: &str) -> ParseResult<(Module, String)> {
+        let module = self.namespaces[namespace].clone();
+        self.expect(TokenData::Dot)?;
+        Ok((module, self.parse_ident()?))
+    }
+
+    /// Parses the `.Variant` following the name of an enum
+    fn parse_variant(
+        &mut self,
+        loc: Loc,
+        name: String,
+        variants: &[(String, usize)],
+    ) -> ParseResult<Node> {
+        self.expect(TokenData::Dot)?;
+        let variant = self.parse_ident()?;
+        match variants.iter().find(|(x, _)| *x == variant) {
+            Some(&(_, value)) => Ok(Node::EnumConst(loc, name, value)),
+            None => Err(Error(loc, format!("Unknown variant: {name}.{variant}"))),
+        }
+    }
+
+    /// Splits a line of an `asm` block into text and placeholders. Constants
+    /// are substituted right away, and `{{`/`}}` stand for literal braces.
+    fn parse_asm_line(&self, loc: &Loc, line: &str, parts: &mut Vec<AsmPart>) -> ParseResult<()> {
+        let mut text = String::new();
+        let mut chars = line.chars().peekable();
+        while let Some(ch) = chars.next() {
+            match ch {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let name: String = chars.by_ref().take_while(|&ch| ch != '}').collect();
+                    let name = name.trim();
+                    if name.is_empty() || !name.chars().all(|ch| ch.is_alphanumeric() || ch == '_')
+                    {
+                        return Err(Error(
+                            loc.clone(),
+                            format!("Invalid placeholder: {{{name}}}"),
+                        ));
+                    }
+                    if let Some(value) = self.consts.get(name) {
+                        text.push_str(&value.to_string());
+                    } else {
+                        parts.push(AsmPart::Text(std::mem::take(&mut text)));
+                        parts.push(AsmPart::Var(name.into()));
+                    }
+                }
+                ch => text.push(ch),
+            }
+        }
+        text.push('\n');
+        parts.push(AsmPart::Text(text));
+        Ok(())
+    }
+
+    /// Parses a parenthesized argument list
+    fn parse_args(&mut self) -> ParseResult<NodeList> {
+        self.expect(TokenData::LParen)?;
+        let mut args = Vec::new();
+        while self.peek() != Some(&TokenData::RParen) {
+            args.push(self.parse_expr()?);
+            if let Some(TokenData::Comma) = self.peek() {
+                self.nom();
+            } else {
+                break;
+            }
+        }
+        self.expect(TokenData::RParen)?;
+        Ok(args)
+    }
+
+    /// Parses any `.field` accesses, `[index]`es and calls following `node`
+    fn parse_postfix(&mut self, mut node: Node) -> ParseResult<Node> {
+        loop {
+            let loc = self.loc();
+            match self.peek() {
+                Some(TokenData::Dot) => {
+                    self.nom();
+                    let field = self.parse_ident()?;
+                    node = Node::FieldAccess(loc, Box::new(node), field, 0, 0);
+                }
+                Some(TokenData::LParen) => {
+                    let args = self.parse_args()?;
+                    node = Node::IndirectCall(loc, Box::new(node), args);
+                }
+                Some(TokenData::LBracket) => {
+                    self.nom();
+                    let index = self.parse_expr()?;
+                    self.expect(TokenData::RBracket)?;
+                    node = Node::Index {
+                        loc,
+                        base: Box::new(node),
+                        index: Box::new(index),
+                        elem_size: 0,
+                        len: None,
+                    };
+                }
+                _ => return Ok(node),
+            }
+        }
+    }
+
+    fn parse_buf(&mut self, loc: Loc, is_static: bool) -> ParseResult<Node> {
+        let size = self.parse_const_expr()?;
+        Ok(Node::Buf(loc, size, is_static))
+    }
+
+    /// Parses an expression that must fold to an integer at compile time
+    fn parse_const_expr(&mut self) -> ParseResult<usize> {
+        let loc = self.loc();
+        match self.parse_expr()? {
+            Node::Int(_, value) | Node::EnumConst(_, _, value) => Ok(value),
+            _ => Err(Error(loc, "Expected constant expression".into())),
+        }
+    }
+
+    /// Parses a `match` pattern: a constant or an inclusive `low..high` range
+    fn parse_pattern(&mut self) -> ParseResult<(usize, usize)> {
+        let loc = self.loc();
+        let low = self.parse_const_expr()?;
+        if let Some(TokenData::DotDot) = self.peek() {
+            self.nom();
+            let high = self.parse_const_expr()?;
+            if high < low {
+                return Err(Error(loc, format!("Empty range: {low}..{high}")));
+            }
+            Ok((low, high))
+        } else {
+            Ok((low, low))
+        }
+    }
+
+    fn check_not_const(&self, loc: &Loc, name: &str) -> ParseResult<()> {
+        if self.consts.contains_key(name) {
+            Err(Error(loc.clone(), format!("{name} is a constant")))
+        } else {
+            Ok(())
+        }
+    }
+
     fn parse_bin_op(&mut self, op: BinOp) -> ParseResult<Node> {
         let loc = self.loc();
         let a = self.parse_expr()?;
         let b = self.parse_expr()?;
 
+        // Operations on constants are folded so they end up as immediates
+        if let (Some(a), Some(b)) = (a.const_value(), b.const_value()) {
+            let value = match op {
+                BinOp::Plus => Some(a.wrapping_add(b)),
+                BinOp::Minus => Some(a.wrapping_sub(b)),
+                BinOp::Mult => Some(a.wrapping_mul(b)),
+                BinOp::Div => a.checked_div(b),
+            };
+            if let Some(value) = value {
+                return Ok(Node::Int(loc, value));
+            }
+        }
+
         Ok(Node::BinOp(loc, op, Box::new(a), Box::new(b)))
     }
 
@@ -345,6 +1103,17 @@ impl Parser {
         let a = self.parse_expr()?;
         let b = self.parse_expr()?;
 
+        if let (Some(a), Some(b)) = (a.const_value(), b.const_value()) {
+            let value = match op {
+                CmpOp::Less => a < b,
+                CmpOp::Equal => a == b,
+                CmpOp::Greater => a > b,
+                CmpOp::LtEq => a <= b,
+                CmpOp::GtEq => a >= b,
+            };
+            return Ok(Node::Int(loc, value as usize));
+        }
+
         Ok(Node::CmpOp(loc, op, Box::new(a), Box::new(b)))
     }
 
@@ -376,10 +1145,6 @@ impl Parser {
         } else {
             self.i - 1
         };
-        self.lexer
-            .get(i)
-            .unwrap()
-            .loc
-            .clone()
+        self.lexer.get(i).unwrap().loc.clone()
     }
 }
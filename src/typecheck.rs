@@ -0,0 +1,1006 @@
+use std::{collections::HashMap, fmt::Display};
+
+use crate::{
+    loc::Loc,
+    parser::{AsmPart, BinOp, Node, Type},
+};
+
+#[derive(Debug)]
+pub struct TypeError(Loc, String);
+
+impl Display for TypeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let TypeError(loc, msg) = self;
+        f.write_fmt(format_args!("{}: {}", loc, msg))
+    }
+}
+
+struct FuncSig {
+    params: Vec<Type>,
+    ret: Type,
+    /// Takes any number of scalars after `params`
+    variadic: bool,
+    /// Extern functions the linker knows under another name
+    symbol: Option<String>,
+}
+
+struct StructLayout {
+    /// Name, type and offset of every field
+    fields: Vec<(String, Type, usize)>,
+    size: usize,
+}
+
+type Scope = Vec<(String, Type)>;
+
+/// Arguments passed in registers under the System V ABI
+const CALL_ARGS: usize = 6;
+/// Arguments a system call takes at most, after its number
+const SYSCALL_ARGS: usize = 6;
+
+struct Checker {
+    funcs: HashMap<String, FuncSig>,
+    structs: HashMap<String, StructLayout>,
+    enums: HashMap<String, Vec<(String, usize)>>,
+}
+
+/// Checks the whole program, filling in the inferred type of every
+/// unannotated `let` so later passes can rely on it.
+pub fn check(node: &mut Node) -> Result<(), TypeError> {
+    let mut checker = Checker {
+        funcs: HashMap::new(),
+        structs: HashMap::new(),
+        enums: HashMap::new(),
+    };
+    checker.collect_decls(node)?;
+    checker.visit(node, &mut Vec::new(), &Type::Int)?;
+    Ok(())
+}
+
+/// A function declared under one of the runtime's `jacl_` names
+struct RuntimeDecl {
+    loc: Loc,
+    name: String,
+    symbol: String,
+    ty: Type,
+    variadic: bool,
+}
+
+/// What the files under std/ declare and define, gathered one file at a time
+/// so they can be compared once all of them are checked
+#[derive(Default)]
+pub struct RuntimeSigs {
+    declared: Vec<RuntimeDecl>,
+    /// Exported definitions by name, with their types
+    defined: HashMap<String, (Loc, Type)>,
+}
+
+fn fun_type(args: &[(String, Option<Type>)], ret: &Option<Type>) -> Type {
+    let params = args
+        .iter()
+        .map(|(_, ty)| ty.clone().unwrap_or(Type::Int))
+        .collect();
+    Type::Fun(params, Box::new(ret.clone().unwrap_or(Type::Int)))
+}
+
+impl RuntimeSigs {
+    /// Adds the top-level declarations and definitions of a checked file
+    pub fn add(&mut self, node: &Node) {
+        match node {
+            Node::Block(_, statements) => {
+                for statement in statements {
+                    self.add(statement);
+                }
+            }
+            Node::ExternFun {
+                loc,
+                name,
+                args,
+                ret,
+                variadic,
+                symbol,
+            } => {
+                let symbol = symbol.as_ref().unwrap_or(name);
+                // Every file sees the prelude, so most come up once per file
+                if symbol.starts_with("jacl_") && !self.declared.iter().any(|d| d.symbol == *symbol)
+                {
+                    self.declared.push(RuntimeDecl {
+                        loc: loc.clone(),
+                        name: name.clone(),
+                        symbol: symbol.clone(),
+                        ty: fun_type(args, ret),
+                        variadic: *variadic,
+                    });
+                }
+            }
+            Node::FuncDef {
+                loc,
+                name,
+                args,
+                ret,
+                export: true,
+                ..
+            } => {
+                self.defined
+                    .insert(name.clone(), (loc.clone(), fun_type(args, ret)));
+            }
+            _ => {}
+        }
+    }
+
+    /// Makes sure every declared function is defined with the same types, so
+    /// the prelude can't drift apart from the runtime. Variadic declarations
+    /// only have their fixed parameters compared.
+    pub fn check(&self) -> Result<(), TypeError> {
+        for decl in &self.declared {
+            let Some((def_loc, def_ty)) = self.defined.get(&decl.symbol) else {
+                return Err(TypeError(
+                    decl.loc.clone(),
+                    format!(
+                        "{} is declared as {}, which the runtime doesn't define",
+                        decl.name, decl.symbol
+                    ),
+                ));
+            };
+            let matches = match (&decl.ty, def_ty) {
+                (Type::Fun(params, ret), Type::Fun(def_params, def_ret)) if decl.variadic => {
+                    ret == def_ret && def_params.starts_with(params)
+                }
+                (ty, def_ty) => ty == def_ty,
+            };
+            if !matches {
+                return Err(TypeError(
+                    decl.loc.clone(),
+                    format!(
+                        "{} is declared as {}, but {} at {def_loc} is {def_ty}",
+                        decl.name, decl.ty, decl.symbol
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_integral(ty: &Type) -> bool {
+    matches!(
+        ty,
+        Type::Int | Type::Byte | Type::Word | Type::Dword | Type::Bool | Type::Enum(_)
+    )
+}
+
+fn is_pointer(ty: &Type) -> bool {
+    matches!(ty, Type::Ptr(_) | Type::Str)
+}
+
+/// Scalars are the types that fit in a register
+fn is_scalar(ty: &Type) -> bool {
+    is_integral(ty) || is_pointer(ty) || matches!(ty, Type::Fun(..))
+}
+
+/// Size in bytes of a scalar stored in memory, which is also its alignment
+fn scalar_size(ty: &Type) -> usize {
+    match ty {
+        Type::Byte => 1,
+        Type::Word => 2,
+        Type::Dword => 4,
+        Type::Int | Type::Bool | Type::Enum(_) | Type::Str | Type::Ptr(_) | Type::Fun(..) => 8,
+        Type::Void | Type::Struct(_) | Type::Array(..) => panic!("{ty} is not a scalar"),
+    }
+}
+
+/// Arrays are used through a pointer to their first element
+fn decay(ty: Type) -> Type {
+    match ty {
+        Type::Array(elem, _) => Type::Ptr(elem),
+        ty => ty,
+    }
+}
+
+fn layout(fields: &[(String, Type)]) -> StructLayout {
+    let mut offset: usize = 0;
+    let mut align = 1;
+    let mut laid_out = Vec::new();
+    for (name, ty) in fields {
+        let size = scalar_size(ty);
+        offset = offset.next_multiple_of(size);
+        laid_out.push((name.clone(), ty.clone(), offset));
+        offset += size;
+        align = align.max(size);
+    }
+    StructLayout {
+        fields: laid_out,
+        size: offset.next_multiple_of(align),
+    }
+}
+
+/// Integral types convert into each other freely, and `*byte` works as the
+/// untyped pointer: every pointer (and `str`) converts to it, and it converts
+/// to every pointer.
+fn assignable(to: &Type, from: &Type) -> bool {
+    match (to, from) {
+        _ if to == from => true,
+        _ if is_integral(to) && is_integral(from) => true,
+        (Type::Ptr(to), Type::Ptr(from)) => **to == Type::Byte || **from == Type::Byte,
+        (Type::Ptr(to), Type::Str) => **to == Type::Byte,
+        (Type::Ptr(_), Type::Array(elem, _)) => assignable(to, &Type::Ptr(elem.clone())),
+        // Only array literals have array types, and they may leave the rest
+        // of the array zeroed
+        (Type::Array(to, to_len), Type::Array(from, from_len)) => {
+            from_len <= to_len && (*from_len == 0 || assignable(to, from))
+        }
+        _ => false,
+    }
+}
+
+/// Checks a value against the type it's used as. A literal 0 is also the
+/// null pointer, and the null str.
+fn expect(value: &Node, expected: &Type, got: &Type) -> Result<(), TypeError> {
+    let null = matches!(value, Node::Int(_, 0)) && is_pointer(expected);
+    if null || assignable(expected, got) {
+        Ok(())
+    } else {
+        Err(TypeError(
+            value.loc().clone(),
+            format!("Type mismatch: expected {expected}, got {got}"),
+        ))
+    }
+}
+
+/// Makes sure the format string of a `fmt` call is a literal whose
+/// directives match the values after it
+fn check_format(args: &[Node], types: &[Type]) -> Result<(), TypeError> {
+    let Node::StrLit(loc, format) = &args[0] else {
+        return Err(TypeError(
+            args[0].loc().clone(),
+            "fmt needs a string literal as its format".into(),
+        ));
+    };
+
+    let mut values = args[1..].iter().zip(&types[1..]);
+    let mut directives = 0;
+    let mut chars = format.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '%' {
+            continue;
+        }
+        let directive = match chars.next() {
+            Some('%') => continue,
+            Some(directive @ ('d' | 'x' | 'c' | 's')) => directive,
+            Some(other) => {
+                return Err(TypeError(
+                    loc.clone(),
+                    format!("Unknown format directive %{other}"),
+                ))
+            }
+            None => {
+                return Err(TypeError(
+                    loc.clone(),
+                    "Format ends in the middle of a directive".into(),
+                ))
+            }
+        };
+        directives += 1;
+
+        let Some((value, ty)) = values.next() else {
+            continue;
+        };
+        let fits = match directive {
+            's' => *ty == Type::Str,
+            // Addresses print fine in hex
+            'x' => is_integral(ty) || is_pointer(ty),
+            _ => is_integral(ty),
+        };
+        if !fits {
+            return Err(TypeError(
+                value.loc().clone(),
+                format!("%{directive} can't format a value of type {ty}"),
+            ));
+        }
+    }
+
+    if directives != args.len() - 1 {
+        return Err(TypeError(
+            loc.clone(),
+            format!(
+                "Format has {directives} directives, but {} values were given",
+                args.len() - 1
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn lookup(loc: &Loc, scope: &Scope, name: &String) -> Result<Type, TypeError> {
+    if let Some((_, ty)) = scope.iter().find(|(x, _)| x == name) {
+        Ok(ty.clone())
+    } else {
+        Err(TypeError(
+            loc.clone(),
+            format!("Undeclared variable: {}", name),
+        ))
+    }
+}
+
+fn deref(loc: &Loc, ptr: Type) -> Result<Type, TypeError> {
+    match ptr {
+        Type::Ptr(ty) if is_scalar(&ty) => Ok(*ty),
+        ty => Err(TypeError(
+            loc.clone(),
+            format!("Cannot dereference value of type {ty}"),
+        )),
+    }
+}
+
+impl Checker {
+    /// Registers every struct and function signature up front so they may be
+    /// used before the definition.
+    fn collect_decls(&mut self, node: &Node) -> Result<(), TypeError> {
+        match node {
+            Node::Block(_, nodes) => {
+                for node in nodes {
+                    self.collect_decls(node)?;
+                }
+            }
+            Node::If {
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                self.collect_decls(then_branch)?;
+                if let Some(else_branch) = else_branch {
+                    self.collect_decls(else_branch)?;
+                }
+            }
+            Node::While { body, .. } => self.collect_decls(body)?,
+            Node::Match { arms, default, .. } => {
+                for (_, _, body) in arms {
+                    self.collect_decls(body)?;
+                }
+                if let Some(default) = default {
+                    self.collect_decls(default)?;
+                }
+            }
+            Node::FuncDef {
+                loc,
+                name,
+                args,
+                ret,
+                body,
+                ..
+            } => {
+                // Anything past six would go on the stack, which neither
+                // calls nor prologues support yet
+                if args.len() > CALL_ARGS {
+                    return Err(TypeError(
+                        loc.clone(),
+                        format!("Function {name} takes more than {CALL_ARGS} arguments"),
+                    ));
+                }
+                self.declare_fun(loc, name, args, ret, false)?;
+                self.collect_decls(body)?;
+            }
+            Node::ExternFun {
+                loc,
+                name,
+                args,
+                ret,
+                variadic,
+                symbol,
+            } => {
+                self.declare_fun(loc, name, args, ret, *variadic)?;
+                self.funcs.get_mut(name).unwrap().symbol = symbol.clone();
+            }
+            Node::StructDef { loc, name, fields } => {
+                if self.structs.contains_key(name) {
+                    return Err(TypeError(
+                        loc.clone(),
+                        format!("Already defined struct: {}", name),
+                    ));
+                }
+                if let Some((field, ty)) = fields.iter().find(|(_, ty)| !is_scalar(ty)) {
+                    return Err(TypeError(
+                        loc.clone(),
+                        format!("Field {field} can't have type {ty}, only integers and pointers can be fields"),
+                    ));
+                }
+                self.structs.insert(name.clone(), layout(fields));
+            }
+            Node::EnumDef {
+                loc,
+                name,
+                variants,
+            } => {
+                if self.enums.contains_key(name) {
+                    return Err(TypeError(
+                        loc.clone(),
+                        format!("Already defined enum: {}", name),
+                    ));
+                }
+                self.enums.insert(name.clone(), variants.clone());
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn declare_fun(
+        &mut self,
+        loc: &Loc,
+        name: &str,
+        args: &[(String, Option<Type>)],
+        ret: &Option<Type>,
+        variadic: bool,
+    ) -> Result<(), TypeError> {
+        if self.funcs.contains_key(name) {
+            return Err(TypeError(
+                loc.clone(),
+                format!("Already defined function: {}", name),
+            ));
+        }
+        let sig = FuncSig {
+            params: args
+                .iter()
+                .map(|(_, ty)| ty.clone().unwrap_or(Type::Int))
+                .collect(),
+            ret: ret.clone().unwrap_or(Type::Int),
+            variadic,
+            symbol: None,
+        };
+        self.funcs.insert(name.into(), sig);
+        Ok(())
+    }
+
+    /// Makes sure a function's signature only has types that fit in registers
+    fn check_sig(&self, loc: &Loc, name: &str) -> Result<(), TypeError> {
+        let sig = &self.funcs[name];
+        for ty in &sig.params {
+            self.check_scalar(loc, ty)?;
+        }
+        if sig.ret != Type::Void {
+            self.check_scalar(loc, &sig.ret)?;
+        }
+        Ok(())
+    }
+
+    /// Makes sure every struct named in `ty` exists
+    fn check_type(&self, loc: &Loc, ty: &Type) -> Result<(), TypeError> {
+        match ty {
+            Type::Ptr(ty) | Type::Array(ty, _) => self.check_type(loc, ty),
+            Type::Fun(params, ret) => {
+                for param in params {
+                    self.check_scalar(loc, param)?;
+                }
+                match **ret {
+                    Type::Void => Ok(()),
+                    ref ret => self.check_scalar(loc, ret),
+                }
+            }
+            Type::Struct(name) if !self.structs.contains_key(name) => {
+                Err(TypeError(loc.clone(), format!("Unknown type: {}", name)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Makes sure values of `ty` can be kept in a variable or passed around
+    fn check_scalar(&self, loc: &Loc, ty: &Type) -> Result<(), TypeError> {
+        self.check_type(loc, ty)?;
+        if is_scalar(ty) {
+            Ok(())
+        } else {
+            Err(TypeError(
+                loc.clone(),
+                format!("Values of type {ty} can't be used here, use a pointer instead"),
+            ))
+        }
+    }
+
+    fn size_of(&self, ty: &Type) -> Option<usize> {
+        match ty {
+            Type::Void => None,
+            Type::Struct(name) => Some(self.structs[name].size),
+            Type::Array(elem, len) => Some(self.size_of(elem)? * len),
+            ty => Some(scalar_size(ty)),
+        }
+    }
+
+    fn visit(&self, node: &mut Node, scope: &mut Scope, ret: &Type) -> Result<Type, TypeError> {
+        Ok(match node {
+            Node::FuncCall(loc, name, args) => {
+                if scope.iter().any(|(x, _)| x == name) {
+                    // A variable holding a function pointer
+                    let callee = Box::new(Node::VarAccess(loc.clone(), name.clone()));
+                    *node = Node::IndirectCall(loc.clone(), callee, std::mem::take(args));
+                    return self.visit(node, scope, ret);
+                }
+                if name == "syscall" && !self.funcs.contains_key(name) {
+                    *node = Node::Syscall(loc.clone(), std::mem::take(args));
+                    return self.visit(node, scope, ret);
+                }
+                if name == "exit" && !self.funcs.contains_key(name) {
+                    let [code] = std::mem::take(args).try_into().map_err(|args: Vec<_>| {
+                        TypeError(
+                            loc.clone(),
+                            format!("exit takes 1 argument, but {} were given", args.len()),
+                        )
+                    })?;
+                    *node = Node::Exit(loc.clone(), Box::new(code));
+                    return self.visit(node, scope, ret);
+                }
+                let Some(sig) = self.funcs.get(name) else {
+                    return Err(TypeError(
+                        loc.clone(),
+                        format!("Unknown function: {}", name),
+                    ));
+                };
+                let arg_types = self.check_args(loc, name, sig, args, scope, ret)?;
+                if name == "fmt" {
+                    check_format(args, &arg_types)?;
+                }
+                // Past this point calls only matter to the linker
+                if let Some(symbol) = &sig.symbol {
+                    *name = symbol.clone();
+                }
+                sig.ret.clone()
+            }
+            Node::IndirectCall(loc, callee, args) => {
+                let ty = self.visit(callee, scope, ret)?;
+                let Type::Fun(params, fun_ret) = &ty else {
+                    return Err(TypeError(
+                        loc.clone(),
+                        format!("Cannot call value of type {ty}"),
+                    ));
+                };
+                let sig = FuncSig {
+                    params: params.clone(),
+                    ret: (**fun_ret).clone(),
+                    variadic: false,
+                    symbol: None,
+                };
+                self.check_args(loc, &ty.to_string(), &sig, args, scope, ret)?;
+                sig.ret
+            }
+            Node::Syscall(loc, args) => {
+                if args.is_empty() || args.len() > SYSCALL_ARGS + 1 {
+                    return Err(TypeError(
+                        loc.clone(),
+                        format!(
+                            "syscall takes a number and up to {SYSCALL_ARGS} arguments, but {} were given",
+                            args.len()
+                        ),
+                    ));
+                }
+                for arg in args {
+                    let ty = decay(self.visit(arg, scope, ret)?);
+                    self.check_scalar(arg.loc(), &ty)?;
+                }
+                Type::Int
+            }
+            Node::Exit(_, code) => {
+                let ty = self.visit(code, scope, ret)?;
+                expect(code, &Type::Int, &ty)?;
+                Type::Void
+            }
+            Node::FuncAddr(loc, name) => {
+                let sig = &self.funcs[name];
+                if sig.variadic {
+                    return Err(TypeError(
+                        loc.clone(),
+                        format!("Cannot take the address of variadic function {name}"),
+                    ));
+                }
+                let ty = Type::Fun(sig.params.clone(), Box::new(sig.ret.clone()));
+                if let Some(symbol) = &sig.symbol {
+                    *name = symbol.clone();
+                }
+                ty
+            }
+            Node::StrLit(_, _) => Type::Str,
+            Node::Block(_, nodes) => {
+                for node in nodes {
+                    self.visit(node, scope, ret)?;
+                }
+                Type::Void
+            }
+            Node::VarDecl(loc, name, ty, value) => {
+                let value_ty = self.visit(value, scope, ret)?;
+                if let Some(ty) = &ty {
+                    match ty {
+                        Type::Array(elem, _) => self.check_scalar(loc, elem)?,
+                        ty => self.check_scalar(loc, ty)?,
+                    }
+                    expect(value, ty, &value_ty)?;
+                } else if value_ty == Type::Void {
+                    return Err(TypeError(
+                        loc.clone(),
+                        format!("Cannot declare variable {} of type void", name),
+                    ));
+                } else if value_ty == Type::Array(Box::new(Type::Void), 0) {
+                    return Err(TypeError(
+                        loc.clone(),
+                        format!("Cannot infer the type of empty array {}", name),
+                    ));
+                } else {
+                    *ty = Some(value_ty);
+                }
+                if let Some(Type::Array(elem, len)) = ty {
+                    // The literal's storage becomes the array's storage
+                    let Node::ArrayLit {
+                        elem_size,
+                        len: storage_len,
+                        ..
+                    } = &mut **value
+                    else {
+                        return Err(TypeError(
+                            value.loc().clone(),
+                            "Arrays must be initialized with an array literal".into(),
+                        ));
+                    };
+                    *elem_size = scalar_size(elem);
+                    *storage_len = *len;
+                }
+                scope.push((name.clone(), ty.clone().unwrap()));
+                Type::Void
+            }
+            Node::VarAccess(loc, name) => lookup(loc, scope, name)?,
+            Node::VarAssign(loc, name, value) => {
+                let ty = lookup(loc, scope, name)?;
+                if let Type::Array(..) = ty {
+                    return Err(TypeError(
+                        loc.clone(),
+                        format!("Cannot assign to array {}", name),
+                    ));
+                }
+                let value_ty = self.visit(value, scope, ret)?;
+                expect(value, &ty, &value_ty)?;
+                Type::Void
+            }
+            Node::Int(_, _) => Type::Int,
+            Node::BinOp(loc, op, a, b) => {
+                let a = decay(self.visit(a, scope, ret)?);
+                let b = decay(self.visit(b, scope, ret)?);
+                match (*op, &a, &b) {
+                    _ if is_integral(&a) && is_integral(&b) => Type::Int,
+                    (BinOp::Plus | BinOp::Minus, Type::Ptr(_), b) if is_integral(b) => a.clone(),
+                    (BinOp::Plus, a, Type::Ptr(_)) if is_integral(a) => b.clone(),
+                    (BinOp::Minus, Type::Ptr(_), Type::Ptr(_)) if assignable(&a, &b) => Type::Int,
+                    _ => {
+                        return Err(TypeError(
+                            loc.clone(),
+                            format!("Cannot apply {op:?} to {a} and {b}"),
+                        ))
+                    }
+                }
+            }
+            Node::CmpOp(loc, op, a, b) => {
+                let a = decay(self.visit(a, scope, ret)?);
+                let b = decay(self.visit(b, scope, ret)?);
+                let both_integral = is_integral(&a) && is_integral(&b);
+                let both_pointers =
+                    is_pointer(&a) && is_pointer(&b) && (assignable(&a, &b) || assignable(&b, &a));
+                if !both_integral && !both_pointers {
+                    return Err(TypeError(
+                        loc.clone(),
+                        format!("Cannot compare {a} and {b} with {op:?}"),
+                    ));
+                }
+                Type::Bool
+            }
+            Node::If {
+                loc: _,
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                self.visit_cond(cond, scope, ret)?;
+                self.visit(then_branch, scope, ret)?;
+                if let Some(else_branch) = else_branch {
+                    self.visit(else_branch, scope, ret)?;
+                }
+                Type::Void
+            }
+            Node::Nop(_) | Node::Break(_) => Type::Void,
+            Node::While { loc: _, cond, body } => {
+                self.visit_cond(cond, scope, ret)?;
+                self.visit(body, scope, ret)?;
+                Type::Void
+            }
+            Node::EnumDef { .. } => Type::Void,
+            Node::ExternFun { loc, name, .. } => {
+                self.check_sig(loc, name)?;
+                Type::Void
+            }
+            Node::Asm(loc, parts, _) => {
+                for part in parts {
+                    if let AsmPart::Var(name) = part {
+                        lookup(loc, scope, name)?;
+                    }
+                }
+                Type::Void
+            }
+            Node::EnumConst(_, name, _) => Type::Enum(name.clone()),
+            Node::Match {
+                loc,
+                value,
+                arms,
+                default,
+            } => {
+                let ty = self.visit(value, scope, ret)?;
+                if !is_integral(&ty) {
+                    return Err(TypeError(
+                        value.loc().clone(),
+                        format!("Cannot match on values of type {ty}"),
+                    ));
+                }
+
+                let mut seen: Vec<(usize, usize)> = Vec::new();
+                for (arm_loc, patterns, body) in arms {
+                    for &(low, high) in patterns.iter() {
+                        if seen.iter().any(|&(l, h)| low <= h && l <= high) {
+                            return Err(TypeError(
+                                arm_loc.clone(),
+                                "Pattern overlaps an earlier one".into(),
+                            ));
+                        }
+                        seen.push((low, high));
+                    }
+                    self.visit(body, scope, ret)?;
+                }
+                if let Some(default) = default {
+                    self.visit(default, scope, ret)?;
+                } else if let Type::Enum(name) = &ty {
+                    let missing: Vec<_> = self.enums[name]
+                        .iter()
+                        .filter(|&&(_, v)| !seen.iter().any(|&(l, h)| l <= v && v <= h))
+                        .map(|(variant, _)| format!("{name}.{variant}"))
+                        .collect();
+                    if !missing.is_empty() {
+                        return Err(TypeError(
+                            loc.clone(),
+                            format!("Match on {name} doesn't handle {}", missing.join(", ")),
+                        ));
+                    }
+                }
+                Type::Void
+            }
+            Node::FuncDef {
+                loc,
+                name,
+                args,
+                ret: _,
+                body,
+                ..
+            } => {
+                self.check_sig(loc, name)?;
+                let sig = &self.funcs[name];
+                let mut body_scope = args
+                    .iter()
+                    .zip(&sig.params)
+                    .map(|((arg, _), ty)| (arg.clone(), ty.clone()))
+                    .collect();
+                self.visit(body, &mut body_scope, &sig.ret)?;
+                Type::Void
+            }
+            Node::Buf(..) => Type::Ptr(Box::new(Type::Byte)),
+            Node::PtrAccess(loc, ptr, size) => {
+                let ty = deref(loc, decay(self.visit(ptr, scope, ret)?))?;
+                *size = scalar_size(&ty);
+                ty
+            }
+            Node::PtrAssign(loc, ptr, value, size) => {
+                let ty = deref(loc, decay(self.visit(ptr, scope, ret)?))?;
+                let value_ty = self.visit(value, scope, ret)?;
+                expect(value, &ty, &value_ty)?;
+                *size = scalar_size(&ty);
+                Type::Void
+            }
+            Node::StructDef { loc, fields, .. } => {
+                for (_, ty) in fields {
+                    self.check_type(loc, ty)?;
+                }
+                Type::Void
+            }
+            Node::FieldAccess(loc, ptr, field, offset, size) => {
+                let ptr_ty = self.visit(ptr, scope, ret)?;
+                let Type::Ptr(ty) = &ptr_ty else {
+                    return Err(TypeError(
+                        loc.clone(),
+                        format!("Cannot access field {field} of {ptr_ty}"),
+                    ));
+                };
+                let Type::Struct(name) = &**ty else {
+                    return Err(TypeError(
+                        loc.clone(),
+                        format!("Cannot access field {field} of {ptr_ty}"),
+                    ));
+                };
+                let layout = &self.structs[name];
+                let Some((_, ty, field_offset)) = layout.fields.iter().find(|(x, ..)| x == field)
+                else {
+                    return Err(TypeError(
+                        loc.clone(),
+                        format!("Struct {name} has no field {field}"),
+                    ));
+                };
+                *offset = *field_offset;
+                *size = scalar_size(ty);
+                ty.clone()
+            }
+            Node::FieldAssign(_, target, value) | Node::IndexAssign(_, target, value) => {
+                let ty = self.visit(target, scope, ret)?;
+                let value_ty = self.visit(value, scope, ret)?;
+                expect(value, &ty, &value_ty)?;
+                Type::Void
+            }
+            Node::SizeOf(loc, ty, size) => {
+                self.check_type(loc, ty)?;
+                let Some(ty_size) = self.size_of(ty) else {
+                    return Err(TypeError(loc.clone(), format!("{ty} has no size")));
+                };
+                *size = ty_size;
+                Type::Int
+            }
+            Node::Cast(loc, ty, value) => {
+                self.check_type(loc, ty)?;
+                let value_ty = decay(self.visit(value, scope, ret)?);
+                // Narrower values would need truncating or extending
+                let full = |ty: &Type| is_scalar(ty) && scalar_size(ty) == 8;
+                if !full(ty) || !full(&value_ty) {
+                    return Err(TypeError(
+                        loc.clone(),
+                        format!("Cannot cast {value_ty} to {ty}, only 8-byte scalars can be cast"),
+                    ));
+                }
+                ty.clone()
+            }
+            Node::Index {
+                loc,
+                base,
+                index,
+                elem_size,
+                len,
+            } => {
+                let base_ty = self.visit(base, scope, ret)?;
+                let index_ty = self.visit(index, scope, ret)?;
+                if !is_integral(&index_ty) {
+                    return Err(TypeError(
+                        index.loc().clone(),
+                        format!("Index must be an integer, got {index_ty}"),
+                    ));
+                }
+                let elem = match base_ty {
+                    Type::Array(elem, array_len) => {
+                        *len = Some(array_len);
+                        *elem
+                    }
+                    Type::Ptr(elem) if is_scalar(&elem) => *elem,
+                    ty => {
+                        return Err(TypeError(
+                            loc.clone(),
+                            format!("Cannot index into value of type {ty}"),
+                        ))
+                    }
+                };
+                *elem_size = scalar_size(&elem);
+                elem
+            }
+            Node::ArrayLit {
+                loc: _,
+                elems,
+                elem_size,
+                len,
+            } => {
+                let mut elem_ty = None;
+                for elem in elems.iter_mut() {
+                    let ty = self.visit(elem, scope, ret)?;
+                    match &elem_ty {
+                        None if is_scalar(&ty) => elem_ty = Some(ty),
+                        None => {
+                            return Err(TypeError(
+                                elem.loc().clone(),
+                                format!("Array elements can't have type {ty}"),
+                            ))
+                        }
+                        Some(elem_ty) => expect(elem, elem_ty, &ty)?,
+                    }
+                }
+                let Some(elem_ty) = elem_ty else {
+                    return Ok(Type::Array(Box::new(Type::Void), 0));
+                };
+                *elem_size = scalar_size(&elem_ty);
+                *len = elems.len();
+                Type::Array(Box::new(elem_ty), elems.len())
+            }
+            Node::VarAddr(loc, name) => match lookup(loc, scope, name) {
+                Ok(ty) => Type::Ptr(Box::new(ty)),
+                Err(err) if !self.funcs.contains_key(name) => return Err(err),
+                Err(_) => {
+                    *node = Node::FuncAddr(loc.clone(), name.clone());
+                    return self.visit(node, scope, ret);
+                }
+            },
+            Node::Return(loc, value) => {
+                if let Node::Nop(_) = **value {
+                    if *ret != Type::Void {
+                        return Err(TypeError(
+                            loc.clone(),
+                            format!("Missing return value, expected {ret}"),
+                        ));
+                    }
+                    return Ok(Type::Void);
+                }
+                let ty = self.visit(value, scope, ret)?;
+                expect(value, ret, &ty)?;
+                Type::Void
+            }
+        })
+    }
+
+    fn check_args(
+        &self,
+        loc: &Loc,
+        callee: &str,
+        sig: &FuncSig,
+        args: &mut [Node],
+        scope: &mut Scope,
+        ret: &Type,
+    ) -> Result<Vec<Type>, TypeError> {
+        let FuncSig {
+            params, variadic, ..
+        } = sig;
+        // Anything past the registers would go on the stack
+        if args.len() > CALL_ARGS {
+            return Err(TypeError(
+                loc.clone(),
+                format!(
+                    "Calls pass at most {CALL_ARGS} arguments, but {} were given",
+                    args.len()
+                ),
+            ));
+        }
+        if *variadic && args.len() < params.len() {
+            return Err(TypeError(
+                loc.clone(),
+                format!(
+                    "Function {} takes at least {} arguments, but {} were given",
+                    callee,
+                    params.len(),
+                    args.len()
+                ),
+            ));
+        }
+        if !*variadic && args.len() != params.len() {
+            return Err(TypeError(
+                loc.clone(),
+                format!(
+                    "Function {} takes {} arguments, but {} were given",
+                    callee,
+                    params.len(),
+                    args.len()
+                ),
+            ));
+        }
+        let mut types = Vec::new();
+        for (i, arg) in args.iter_mut().enumerate() {
+            let ty = self.visit(arg, scope, ret)?;
+            match params.get(i) {
+                Some(param) => expect(arg, param, &ty)?,
+                None => self.check_scalar(arg.loc(), &decay(ty.clone()))?,
+            }
+            types.push(decay(ty));
+        }
+        Ok(types)
+    }
+
+    fn visit_cond(&self, cond: &mut Node, scope: &mut Scope, ret: &Type) -> Result<(), TypeError> {
+        let ty = decay(self.visit(cond, scope, ret)?);
+        if is_integral(&ty) || is_pointer(&ty) {
+            Ok(())
+        } else {
+            Err(TypeError(
+                cond.loc().clone(),
+                format!("Condition must be an integer or a pointer, got {ty}"),
+            ))
+        }
+    }
+}